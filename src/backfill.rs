@@ -0,0 +1,381 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use chrono::Utc;
+
+use crate::config::Config;
+use crate::input;
+use crate::model::{NormalizationError, NormalizeData};
+use crate::store::serde_name;
+
+fn io_error(path: &str, err: std::io::Error) -> NormalizationError {
+    NormalizationError::Unknown(format!("{}: {}", path, err))
+}
+
+/// Moves a processed input file into a new entry of the input archive,
+/// `<input_archive_dir>/<YYYY-MM-DD>/<HHMMSSmmm>_<file name>/`, next to copies of the outputs
+/// written for it, given by name and contents. Returns the entry directory.
+pub fn archive(config: &Config, file_path: &str, file_name: &str, outputs: &[(String, String)]) -> Result<String, NormalizationError> {
+    move_input(&config.input_archive_dir, file_path, file_name, outputs)
+}
+
+/// Moves an input file that could not be normalized in full to `failed_dir`, laid out like the
+/// input archive, so that it is not processed again on every pass. `outputs` holds the outputs
+/// of the members that were normalized and the errors of the others.
+pub fn quarantine(config: &Config, file_path: &str, file_name: &str, outputs: &[(String, String)]) -> Result<String, NormalizationError> {
+    move_input(&config.failed_dir, file_path, file_name, outputs)
+}
+
+fn move_input(dir: &str, file_path: &str, file_name: &str, outputs: &[(String, String)]) -> Result<String, NormalizationError> {
+    let now = Utc::now();
+    let entry = format!("{}/{}/{}_{}", dir, now.format("%Y-%m-%d"), now.format("%H%M%S%3f"), file_name);
+    std::fs::create_dir_all(&entry).map_err(|err| io_error(&entry, err))?;
+    for (name, contents) in outputs {
+        let path = format!("{}/{}", entry, name);
+        std::fs::write(&path, contents).map_err(|err| io_error(&path, err))?;
+    }
+
+    // Renaming fails across file systems, where the file is copied instead.
+    let archived = format!("{}/{}", entry, file_name);
+    std::fs::rename(file_path, &archived)
+        .or_else(|_| std::fs::copy(file_path, &archived).and_then(|_| std::fs::remove_file(file_path)))
+        .map_err(|err| io_error(file_path, err))?;
+    Ok(entry)
+}
+
+/// Names of the subdirectories of a directory in order, none when it does not exist.
+fn subdirectories(path: &str) -> Result<Vec<String>, NormalizationError> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(io_error(path, err)),
+    };
+    let mut names: Vec<String> = entries.flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Which archived inputs a backfill replays. Dates are processing dates as `YYYY-MM-DD`, and both
+/// ends of the range are included. No providers means every provider.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackfillRange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub providers: Vec<String>,
+}
+
+impl BackfillRange {
+    fn includes_date(&self, date: &str) -> bool {
+        self.from.as_deref().is_none_or(|from| date >= from) && self.to.as_deref().is_none_or(|to| date <= to)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStatus {
+    Unchanged,
+    Changed,
+    /// No output was archived for the input, e.g. one its provider used to reject.
+    New,
+    /// The input could not be normalized again.
+    Failed,
+}
+
+/// How the output of one archived input compares with the output it had when processed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputChange {
+    /// Archive entry of the input, e.g. `2024-10-15/093000123_data.a`.
+    pub entry: String,
+    /// Name of the output, e.g. `normalize_data.a.n`.
+    pub output: String,
+    pub status: OutputStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>,
+}
+
+/// Result of a backfill run, also written to `report.json` in its output directory.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BackfillReport {
+    pub output_dir: String,
+    pub outputs: Vec<OutputChange>,
+}
+
+impl BackfillReport {
+    pub fn count(&self, status: OutputStatus) -> usize {
+        self.outputs.iter().filter(|output| output.status == status).count()
+    }
+}
+
+/// Normalizes the archived inputs in `range` again with the current providers and configuration.
+/// Outputs are written to a new directory of `backfill_dir`, mirroring the archive, and compared
+/// with the archived ones. The live input, outputs, database and history are left alone, and
+/// deduplication and versioning, which depend on the database, are not applied.
+pub fn backfill(config: &Config, range: &BackfillRange) -> Result<BackfillReport, NormalizationError> {
+    let output_dir = format!("{}/{}", config.backfill_dir, Utc::now().format("%Y%m%dT%H%M%S%3fZ"));
+    let mut outputs = Vec::new();
+    for date in subdirectories(&config.input_archive_dir)?.into_iter().filter(|date| range.includes_date(date)) {
+        for name in subdirectories(&format!("{}/{}", config.input_archive_dir, date))? {
+            let entry = format!("{}/{}", date, name);
+            let entry_path = format!("{}/{}", config.input_archive_dir, entry);
+            let file_name = name.split_once('_').map_or(name.as_str(), |(_, file_name)| file_name);
+            let file_path = format!("{}/{}", entry_path, file_name);
+            let inputs = std::fs::read(&file_path)
+                .map_err(|err| io_error(&file_path, err))
+                .and_then(|bytes| input::expand(file_name, bytes, &config.archive));
+            let inputs = match inputs {
+                Ok(inputs) => inputs,
+                Err(err) => {
+                    outputs.push(OutputChange {entry, output: String::new(), status: OutputStatus::Failed, changes: vec![format!("{:?}", err)]});
+                    continue;
+                }
+            };
+
+            for input in inputs {
+                let provider = input.extension().map(|extension| config.source(extension).0);
+                if !range.providers.is_empty() && !provider.is_some_and(|provider| range.providers.contains(&provider)) {
+                    continue;
+                }
+                let output = format!("normalize_{}.n", input.output_name());
+                let (status, changes) = match crate::handle_data(config, &input) {
+                    Ok((normalized, _)) => {
+                        let run_dir = format!("{}/{}", output_dir, entry);
+                        std::fs::create_dir_all(&run_dir).map_err(|err| io_error(&run_dir, err))?;
+                        let path = format!("{}/{}", run_dir, output);
+                        std::fs::write(&path, serde_json::json!(normalized).to_string()).map_err(|err| io_error(&path, err))?;
+                        let archived = format!("{}/{}", entry_path, output);
+                        match std::fs::read_to_string(&archived) {
+                            Ok(contents) => {
+                                let original: Vec<NormalizeData> = serde_json::from_str(&contents)
+                                    .map_err(|err| NormalizationError::Parse(format!("{}: {}", archived, err)))?;
+                                let changes = compare(&original, &normalized);
+                                (if changes.is_empty() {OutputStatus::Unchanged} else {OutputStatus::Changed}, changes)
+                            },
+                            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (OutputStatus::New, Vec::new()),
+                            Err(err) => return Err(io_error(&archived, err)),
+                        }
+                    },
+                    Err(err) => (OutputStatus::Failed, vec![format!("{:?}", err)]),
+                };
+                outputs.push(OutputChange {entry: entry.clone(), output, status, changes});
+            }
+        }
+    }
+
+    let report = BackfillReport {output_dir, outputs};
+    std::fs::create_dir_all(&report.output_dir).map_err(|err| io_error(&report.output_dir, err))?;
+    let path = format!("{}/report.json", report.output_dir);
+    std::fs::write(&path, serde_json::json!(report).to_string()).map_err(|err| io_error(&path, err))?;
+    Ok(report)
+}
+
+/// The compared fields of each assessment, by `<patient> <type>[ <record id>]`. Assessments with
+/// the same key are told apart by their order, e.g. `P1 cognitive #2`.
+fn summaries(normalized: &[NormalizeData]) -> BTreeMap<String, BTreeMap<String, String>> {
+    let mut output = BTreeMap::new();
+    for data in normalized {
+        let base = match &data.record_id {
+            Some(record_id) => format!("{} {} {}", data.patientId, data.assessmentType, record_id),
+            None => format!("{} {}", data.patientId, data.assessmentType),
+        };
+        let mut key = base.clone();
+        for occurrence in 2.. {
+            if !output.contains_key(&key) {
+                break;
+            }
+            key = format!("{} #{}", base, occurrence);
+        }
+
+        let mut fields = BTreeMap::from([("status".to_string(), serde_name(&data.status).unwrap_or_default())]);
+        if !data.date_unknown {
+            fields.insert("date".to_string(), data.assessmentDate.clone());
+        }
+        if let Some(flag) = data.flag {
+            fields.insert("flag".to_string(), serde_name(&flag).unwrap_or_default());
+        }
+        if let Some(completeness) = &data.completeness {
+            fields.insert("completeness".to_string(), serde_name(&completeness.status).unwrap_or_default());
+        }
+        for score in &data.scores {
+            let mut value = format!("{} ({}", score.value, score.scale);
+            if let Some(interpretation) = score.interpretation {
+                value.push_str(&format!(", {}", serde_name(&interpretation).unwrap_or_default()));
+            }
+            value.push(')');
+            fields.entry(score.dimension.clone())
+                .and_modify(|values: &mut String| values.push_str(&format!(", {}", value)))
+                .or_insert(value);
+        }
+        output.insert(key, fields);
+    }
+
+    output
+}
+
+/// Differences between two outputs of the same input that a reader would see: assessments added
+/// or removed, and changed dates, scores, statuses, flags and completeness. Processing times,
+/// the dates of assessments whose source gave none, fingerprints and lineage differ on every run
+/// and are not compared.
+pub fn compare(original: &[NormalizeData], regenerated: &[NormalizeData]) -> Vec<String> {
+    let (original, regenerated) = (summaries(original), summaries(regenerated));
+    let mut changes = Vec::new();
+    for (key, before) in &original {
+        let Some(after) = regenerated.get(key) else {
+            changes.push(format!("{}: assessment removed", key));
+            continue;
+        };
+        for (field, value) in before {
+            match after.get(field) {
+                Some(new_value) if new_value == value => {},
+                Some(new_value) => changes.push(format!("{}: {} {} -> {}", key, field, value, new_value)),
+                None => changes.push(format!("{}: {} {} removed", key, field, value)),
+            }
+        }
+        for (field, value) in after.iter().filter(|(field, _)| !before.contains_key(*field)) {
+            changes.push(format!("{}: {} {} added", key, field, value));
+        }
+    }
+    for key in regenerated.keys().filter(|key| !original.contains_key(*key)) {
+        changes.push(format!("{}: assessment added", key));
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ScoreValue;
+    use crate::severity::Severity;
+    use crate::testing::{assessment, config, normalize};
+
+    #[test]
+    fn compare_test() {
+        let original = vec![
+            assessment("P1", "behavioral_screening", "2024-10-15T09:00:00Z", &[("anxiety", 70), ("social", 40)]),
+            assessment("P2", "behavioral_screening", "2024-10-15T09:00:00Z", &[("anxiety", 50)]),
+        ];
+        let mut regenerated = original.clone();
+        regenerated[0].metadata.insert("ingestedAt".into(), Utc::now().to_rfc3339());
+        assert!(compare(&original, &regenerated).is_empty());
+
+        regenerated[0].scores[0].value = ScoreValue::Integer(7);
+        regenerated[0].scores.remove(1);
+        regenerated[1] = assessment("P3", "behavioral_screening", "2024-10-15T09:00:00Z", &[("anxiety", 50)]);
+        assert_eq!(compare(&original, &regenerated), vec![
+            "P1 behavioral_screening: anxiety 70 (0-100) -> 7 (0-100)",
+            "P1 behavioral_screening: social 40 (0-100) removed",
+            "P2 behavioral_screening: assessment removed",
+            "P3 behavioral_screening: assessment added",
+        ]);
+
+        // Source dates are compared; processing dates of undated assessments are not.
+        let mut redated = original.clone();
+        redated[0].assessmentDate = "2024-10-16T09:00:00+00:00".into();
+        assert_eq!(compare(&original, &redated), vec![
+            "P1 behavioral_screening: date 2024-10-15T09:00:00+00:00 -> 2024-10-16T09:00:00+00:00",
+        ]);
+        let undated: Vec<NormalizeData> = original.iter().cloned().map(|data| NormalizeData {date_unknown: true, ..data}).collect();
+        let mut redated = undated.clone();
+        redated[0].assessmentDate = Utc::now().to_rfc3339();
+        assert!(compare(&undated, &redated).is_empty());
+    }
+
+    #[test]
+    fn archive_backfill_test() {
+        let dir = std::env::temp_dir().join(format!("normalize_backfill_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config {
+            input_archive_dir: format!("{}/archive", dir),
+            backfill_dir: format!("{}/backfill", dir),
+            ..config()
+        };
+        std::fs::create_dir_all(format!("{}/input", dir)).unwrap();
+
+        // data.a is archived with an output that the current configuration no longer produces.
+        let mut entries = Vec::new();
+        for name in ["data.a", "data.c"] {
+            let path = format!("{}/input/{}", dir, name);
+            std::fs::copy(format!("test-data/{}", name), &path).unwrap();
+            let mut normalized = normalize(&config, name);
+            if name == "data.a" {
+                normalized[0].scores[0].value = ScoreValue::Integer(60);
+                normalized[0].scores[0].interpretation = Some(Severity::Borderline);
+            }
+            let outputs = vec![(format!("normalize_{}.n", name), serde_json::json!(normalized).to_string())];
+            entries.push(archive(&config, &path, name, &outputs).unwrap());
+            assert!(!std::path::Path::new(&path).exists());
+        }
+
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        let entry = entries[0].strip_prefix(&format!("{}/{}/", config.input_archive_dir, today)).unwrap();
+        let (time, name) = entry.split_once('_').unwrap();
+        assert!(time.len() == 9 && time.chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(name, "data.a");
+        assert!(std::path::Path::new(&format!("{}/data.a", entries[0])).exists());
+        assert!(std::path::Path::new(&format!("{}/normalize_data.a.n", entries[0])).exists());
+
+        let report = backfill(&config, &BackfillRange {from: Some(today.clone()), ..BackfillRange::default()}).unwrap();
+        let outputs: Vec<(&str, OutputStatus)> = report.outputs.iter().map(|output| (output.output.as_str(), output.status)).collect();
+        assert_eq!(outputs, vec![("normalize_data.a.n", OutputStatus::Changed), ("normalize_data.c.n", OutputStatus::Unchanged)]);
+        assert_eq!(report.outputs[0].changes, vec!["P123a behavioral_screening: anxiety 60 (0-100, borderline) -> 70 (0-100, clinical)"]);
+        let written: BackfillReport = serde_json::from_str(&std::fs::read_to_string(format!("{}/report.json", report.output_dir)).unwrap()).unwrap();
+        assert_eq!(written, report);
+        assert!(std::path::Path::new(&format!("{}/{}/normalize_data.c.n", report.output_dir, entries[1].strip_prefix(&format!("{}/", config.input_archive_dir)).unwrap())).exists());
+
+        let yesterday = (Utc::now() - chrono::Duration::days(1)).format("%Y-%m-%d").to_string();
+        assert!(backfill(&config, &BackfillRange {to: Some(yesterday), ..BackfillRange::default()}).unwrap().outputs.is_empty());
+        let report = backfill(&config, &BackfillRange {providers: vec!["c".into()], ..BackfillRange::default()}).unwrap();
+        assert_eq!(report.outputs.iter().map(|output| output.output.as_str()).collect::<Vec<_>>(), vec!["normalize_data.c.n"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn quarantine_test() {
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("normalize_quarantine_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let _ = std::fs::remove_dir_all(dir);
+        let config = Config {
+            input_archive_dir: format!("{}/archive", dir),
+            failed_dir: format!("{}/failed", dir),
+            followup_dir: format!("{}/followup", dir),
+            history_dir: format!("{}/history", dir),
+            timeline_dir: format!("{}/timeline", dir),
+            ..config()
+        };
+        for path in ["input", "normalized", "followup", "history", "timeline"] {
+            std::fs::create_dir_all(format!("{}/{}", dir, path)).unwrap();
+        }
+
+        // One member of the archive normalizes and the other does not.
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer.start_file("data.a", zip::write::SimpleFileOptions::default()).unwrap();
+        writer.write_all(include_bytes!("../test-data/data.a")).unwrap();
+        writer.start_file("broken.a", zip::write::SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"{not json").unwrap();
+        let path = format!("{}/input/bundle.zip", dir);
+        std::fs::write(&path, writer.finish().unwrap().into_inner()).unwrap();
+
+        let mut store = crate::store::Store::open(":memory:").unwrap();
+        crate::process_file(&config, &mut store, &path, "bundle.zip", &format!("{}/normalized", dir));
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(subdirectories(&config.input_archive_dir).unwrap().is_empty());
+        let date = subdirectories(&config.failed_dir).unwrap().remove(0);
+        let entry = format!("{}/{}/{}", config.failed_dir, date, subdirectories(&format!("{}/{}", config.failed_dir, date)).unwrap()[0]);
+        assert!(entry.ends_with("_bundle.zip"));
+        assert!(std::path::Path::new(&format!("{}/bundle.zip", entry)).exists());
+        assert!(std::path::Path::new(&format!("{}/normalize_bundle.zip_data.a.n", entry)).exists());
+        let errors: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(format!("{}/errors.json", entry)).unwrap()).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["input"], "bundle.zip_broken.a");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
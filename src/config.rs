@@ -0,0 +1,413 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+use crate::dedup::DedupConfig;
+use crate::input::ArchiveLimits;
+use crate::instruments::InstrumentCatalog;
+use crate::norms::NormCatalog;
+use crate::model::{Decimal, MissingReason, NormalizationError, ScoreValue};
+use crate::ranges::RangeCatalog;
+use crate::severity::Severity;
+
+/// How a delimited text file is laid out. Every field is optional in the config file.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct CsvDialect {
+    pub delimiter: char,
+    pub quote: char,
+    pub escape: Option<char>,
+    pub comment: Option<char>,
+    pub strip_bom: bool,
+    pub has_headers: bool,
+    pub case_insensitive_headers: bool,
+    /// Source header name -> canonical header name, e.g. `PatientID` -> `patient_id`.
+    pub header_aliases: BTreeMap<String, String>,
+    /// Canonical header names by position, used when `has_headers` is false.
+    pub columns: Vec<String>,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            quote: '"',
+            escape: None,
+            comment: None,
+            strip_bom: true,
+            has_headers: true,
+            case_insensitive_headers: false,
+            header_aliases: BTreeMap::new(),
+            columns: Vec::new(),
+        }
+    }
+}
+
+impl CsvDialect {
+    pub fn reader_builder(&self) -> Result<csv::ReaderBuilder, NormalizationError> {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .has_headers(self.has_headers)
+            .flexible(true)
+            .delimiter(ascii_byte("delimiter", self.delimiter)?)
+            .quote(ascii_byte("quote", self.quote)?)
+            .escape(self.escape.map(|c| ascii_byte("escape", c)).transpose()?)
+            .comment(self.comment.map(|c| ascii_byte("comment", c)).transpose()?);
+        Ok(builder)
+    }
+
+    /// Strips a leading byte order mark when configured to.
+    pub fn prepare<'a>(&self, data: &'a str) -> &'a str {
+        if self.strip_bom {
+            data.strip_prefix('\u{feff}').unwrap_or(data)
+        } else {
+            data
+        }
+    }
+
+    /// Maps a header from the file onto its canonical name.
+    pub fn canonical_header(&self, header: &str) -> String {
+        let header = header.trim();
+        for (alias, canonical) in &self.header_aliases {
+            if alias == header ||
+                (self.case_insensitive_headers && alias.eq_ignore_ascii_case(header)) {
+                return canonical.clone();
+            }
+        }
+
+        if self.case_insensitive_headers {
+            header.to_lowercase()
+        } else {
+            header.to_string()
+        }
+    }
+}
+
+/// Reads every `.json` file in a directory, in name order. A missing directory has no files.
+pub fn load_json_dir<T: serde::de::DeserializeOwned>(path: &str) -> Result<Vec<(String, T)>, NormalizationError> {
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(NormalizationError::Unknown(format!("{}: {}", path, err))),
+    };
+
+    let mut paths: Vec<_> = entries.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    paths.sort();
+
+    let mut output = Vec::new();
+    for path in paths {
+        let name = path.display().to_string();
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|err| NormalizationError::Unknown(format!("{}: {}", name, err)))?;
+        let value = serde_json::from_str(&contents)
+            .map_err(|err| NormalizationError::Parse(format!("{}: {}", name, err)))?;
+        output.push((name, value));
+    }
+
+    Ok(output)
+}
+
+fn ascii_byte(name: &str, c: char) -> Result<u8, NormalizationError> {
+    if c.is_ascii() {
+        Ok(c as u8)
+    } else {
+        Err(NormalizationError::Unknown(format!("CSV {} must be a single ASCII character: {:?}", name, c)))
+    }
+}
+
+/// Wide CSV layout: one row per assessment and one column per metric.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct WideLayout {
+    /// Metric column -> score dimension.
+    pub metrics: BTreeMap<String, String>,
+    /// Assessment type for files without a `category` column.
+    pub assessment_type: Option<String>,
+}
+
+/// Where the data sits in a spreadsheet workbook.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct SheetLayout {
+    /// Sheet name, defaults to the first sheet.
+    pub sheet: Option<String>,
+    /// 1-based row holding the column headers; rows above it are ignored.
+    pub header_row: usize,
+    /// Sheet header -> canonical column name, e.g. `Patient` -> `patient_id`.
+    pub columns: BTreeMap<String, String>,
+    /// Canonical columns whose numeric cells are Excel date serials.
+    pub date_columns: Vec<String>,
+}
+
+impl Default for SheetLayout {
+    fn default() -> Self {
+        Self {
+            sheet: None,
+            header_row: 1,
+            columns: BTreeMap::new(),
+            date_columns: vec!["assessment_date".into()],
+        }
+    }
+}
+
+/// XPath-style locations of assessment data in an XML document. Paths are `/`-separated
+/// element names with optional namespace prefixes, ending in an element (its text) or `@attribute`.
+/// Paths starting with `/` are absolute; others are relative to the record, or to the score for
+/// `dimension` and `value`, where `.` is the score element itself.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct XmlMapping {
+    /// Prefix -> namespace URI. Unprefixed steps match elements in any namespace.
+    pub namespaces: BTreeMap<String, String>,
+    /// Absolute path of the elements holding one assessment each.
+    pub record: String,
+    pub patient_id: String,
+    pub assessment_type: String,
+    pub assessment_date: String,
+    pub score: String,
+    pub dimension: String,
+    pub value: String,
+    /// Identifier of the assessment, shared by its amended versions.
+    pub record_id: String,
+    /// `amended` or `retracted` marker of the assessment, absent for original assessments.
+    pub status: String,
+}
+
+impl Default for XmlMapping {
+    fn default() -> Self {
+        Self {
+            namespaces: BTreeMap::new(),
+            record: "/assessments/assessment".into(),
+            patient_id: "patient/@id".into(),
+            assessment_type: "@type".into(),
+            assessment_date: "@date".into(),
+            score: "score".into(),
+            dimension: "@name".into(),
+            value: ".".into(),
+            record_id: "@id".into(),
+            status: "@status".into(),
+        }
+    }
+}
+
+/// How coerced fractional scores are rounded to integers, if at all.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rounding {
+    /// Round half away from zero.
+    #[default]
+    Nearest,
+    Floor,
+    Ceil,
+    Truncate,
+    /// Reject values with a fractional part.
+    Reject,
+    /// Keep fractional values as decimals.
+    Keep,
+}
+
+impl Rounding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rounding::Nearest => "nearest",
+            Rounding::Floor => "floor",
+            Rounding::Ceil => "ceil",
+            Rounding::Truncate => "truncate",
+            Rounding::Reject => "reject",
+            Rounding::Keep => "keep",
+        }
+    }
+
+    pub fn apply(&self, value: Decimal) -> Option<ScoreValue> {
+        if value.precision == 0 {
+            return Some(ScoreValue::Integer(value.units));
+        }
+
+        let divisor = 10i64.checked_pow(value.precision)?;
+        let (quotient, remainder) = (value.units / divisor, value.units % divisor);
+        let rounded = match self {
+            _ if remainder == 0 => quotient,
+            Rounding::Nearest if remainder.abs() * 2 >= divisor => quotient + remainder.signum(),
+            Rounding::Nearest | Rounding::Truncate => quotient,
+            Rounding::Floor => if remainder < 0 {quotient - 1} else {quotient},
+            Rounding::Ceil => if remainder > 0 {quotient + 1} else {quotient},
+            Rounding::Reject => return None,
+            Rounding::Keep => return Some(ScoreValue::Decimal(value)),
+        };
+        Some(ScoreValue::Integer(rounded))
+    }
+}
+
+/// Which keys of a flat key-value record hold scores, and how their values are read.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct ScoreKeys {
+    /// Key prefixes that mark a score; the rest of the key is the dimension.
+    pub prefixes: Vec<String>,
+    /// Key suffixes that mark a score; the start of the key is the dimension.
+    pub suffixes: Vec<String>,
+    /// Regular expressions matched against the whole key. The dimension is the `dimension`
+    /// capture group, else the first group, else the whole key.
+    pub patterns: Vec<String>,
+    /// Keys holding an object of dimension -> score.
+    pub nested: Vec<String>,
+    /// Accept floats and numeric strings as scores.
+    pub coerce: bool,
+    /// How coerced values with a fractional part become integers, or `keep` for decimals.
+    pub rounding: Rounding,
+    /// Keys that are neither fields nor scores but are expected, so not reported.
+    pub ignore: Vec<String>,
+}
+
+impl Default for ScoreKeys {
+    fn default() -> Self {
+        Self {
+            prefixes: vec!["score_".into()],
+            suffixes: Vec::new(),
+            patterns: Vec::new(),
+            nested: vec!["scores".into()],
+            coerce: false,
+            rounding: Rounding::Nearest,
+            ignore: Vec::new(),
+        }
+    }
+}
+
+/// Source values that mean a score is missing, e.g. `NA` -> `not_applicable` or
+/// `999` -> `not_administered`. Values are matched exactly after trimming whitespace.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(transparent)]
+pub struct MissingTokens(pub BTreeMap<String, MissingReason>);
+
+impl MissingTokens {
+    pub fn reason(&self, value: &str) -> Option<MissingReason> {
+        self.0.get(value.trim()).copied()
+    }
+
+    /// Matches a JSON value by its text; `null` matches like a blank value.
+    pub fn reason_json(&self, value: &serde_json::Value) -> Option<MissingReason> {
+        match value {
+            serde_json::Value::Null => self.reason(""),
+            serde_json::Value::String(text) => self.reason(text),
+            serde_json::Value::Number(number) => self.reason(&number.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Settings for one input source, keyed in `Config::sources` by file extension.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct SourceConfig {
+    /// Provider that handles the source, defaults to the extension itself.
+    pub provider: Option<String>,
+    /// Encoding label such as `windows-1252` or `utf-16le`, used when the file has no BOM.
+    pub encoding: Option<String>,
+    pub csv: CsvDialect,
+    /// Read provider C files as wide CSV instead of one metric per row.
+    pub wide: Option<WideLayout>,
+    /// Source values read as missing scores instead of invalid ones.
+    pub missing: MissingTokens,
+    /// Reference ranges of this source, checked before the global ones.
+    pub ranges: RangeCatalog,
+    /// Score keys of provider B records.
+    pub score_keys: ScoreKeys,
+    pub xlsx: SheetLayout,
+    pub xml: XmlMapping,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub sources: BTreeMap<String, SourceConfig>,
+    pub archive: ArchiveLimits,
+    /// Reference ranges shared by all sources.
+    pub ranges: RangeCatalog,
+    /// Directory of instrument definitions, one JSON file per assessment type.
+    pub instruments_dir: String,
+    #[serde(skip)]
+    pub instruments: InstrumentCatalog,
+    /// Directory of normative tables, one JSON file per assessment type and dimension.
+    pub norms_dir: String,
+    #[serde(skip)]
+    pub norms: NormCatalog,
+    /// Directory of the follow-up output, the assessments flagged at least `followup_severity`.
+    pub followup_dir: String,
+    pub followup_severity: Severity,
+    /// Directory of the longitudinal store, one JSON file of assessments per patient.
+    pub history_dir: String,
+    /// Directory of the per-patient timelines, rewritten when a patient gets a new assessment.
+    pub timeline_dir: String,
+    /// SQLite database of every normalized assessment, created when missing.
+    pub database: String,
+    /// How assessments duplicating ones ingested before are detected and handled.
+    pub dedup: DedupConfig,
+    /// Directory processed input files are moved to, with copies of their outputs, by processing
+    /// date. See backfill.rs.
+    pub input_archive_dir: String,
+    /// Directory of backfill runs, one subdirectory per run.
+    pub backfill_dir: String,
+    /// Directory input files that failed to normalize are moved to, laid out like the input
+    /// archive.
+    pub failed_dir: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sources: BTreeMap::new(),
+            archive: ArchiveLimits::default(),
+            ranges: RangeCatalog::default(),
+            instruments_dir: "./instruments".into(),
+            instruments: InstrumentCatalog::default(),
+            norms_dir: "./norms".into(),
+            norms: NormCatalog::default(),
+            followup_dir: "./followup".into(),
+            followup_severity: Severity::Clinical,
+            history_dir: "./history".into(),
+            timeline_dir: "./timeline".into(),
+            database: "./normalize.db".into(),
+            dedup: DedupConfig::default(),
+            input_archive_dir: "./input_archive".into(),
+            backfill_dir: "./backfill".into(),
+            failed_dir: "./failed".into(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file, falling back to the defaults when it does not exist, and the
+    /// instrument and norm catalogues it points to.
+    pub fn load(path: &str) -> Result<Self, NormalizationError> {
+        let mut config: Self = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|err| NormalizationError::Parse(format!("{}: {}", path, err)))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(NormalizationError::Unknown(format!("{}: {}", path, err))),
+        };
+        for (name, ranges) in std::iter::once(("ranges", &config.ranges))
+            .chain(config.sources.iter().map(|(name, source)| (name.as_str(), &source.ranges))) {
+            ranges.validate().map_err(|err| NormalizationError::Parse(format!("{}: {}: {}", path, name, err)))?;
+        }
+        config.instruments = InstrumentCatalog::load_dir(&config.instruments_dir)?;
+        config.instruments.ranges().validate().map_err(|err| NormalizationError::Parse(format!("{}: {}", config.instruments_dir, err)))?;
+        config.norms = NormCatalog::load_dir(&config.norms_dir)?;
+        Ok(config)
+    }
+
+    /// Resolves a file extension to the provider name and its source settings, with the global
+    /// and instrument reference ranges after the source's own.
+    pub fn source(&self, extension: &str) -> (String, SourceConfig) {
+        let (provider, mut source) = match self.sources.get(extension) {
+            Some(source) => (
+                source.provider.clone().unwrap_or_else(|| extension.to_string()),
+                source.clone()),
+            None => (extension.to_string(), SourceConfig::default()),
+        };
+        source.ranges.0.extend(self.ranges.0.iter().cloned());
+        source.ranges.0.extend(self.instruments.ranges().0);
+        (provider, source)
+    }
+}
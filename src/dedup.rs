@@ -0,0 +1,234 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::model::{NormalizationError, NormalizeData};
+use crate::store::Store;
+
+/// What to do with an assessment that duplicates one ingested before.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupPolicy {
+    /// Leave the duplicate out of the output.
+    Drop,
+    /// Keep the duplicate, with `duplicate` and `duplicateOf` metadata.
+    #[default]
+    Flag,
+    /// Keep the duplicate as a new version of the earlier assessment, which is marked superseded.
+    Supersede,
+}
+
+/// Two assessments of the same patient and type are near duplicates when their dates are at most
+/// `window_hours` apart and at least `similarity` of their source dimensions have equal values.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct DedupConfig {
+    pub policy: DedupPolicy,
+    pub window_hours: i64,
+    pub similarity: f64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            policy: DedupPolicy::Flag,
+            window_hours: 24,
+            similarity: 0.8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKind {
+    /// Same fingerprint.
+    Exact,
+    Near,
+}
+
+/// An assessment that duplicates an earlier one of the same file or of an earlier file.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct DuplicateIssue {
+    /// Index of the normalized assessment.
+    pub record: usize,
+    pub kind: DuplicateKind,
+    pub patient_id: String,
+    pub assessment_type: String,
+    /// Fingerprint of the earlier assessment.
+    pub duplicate_of: String,
+    /// Source file of the earlier assessment.
+    pub source_file: Option<String>,
+}
+
+/// What deduplication compares of an assessment: its source scores as serialized, so derived
+/// scores, which depend on the configuration, are left out, and its date when the source gave one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Candidate {
+    pub fingerprint: String,
+    pub assessment_time: Option<DateTime<Utc>>,
+    pub scores: BTreeMap<String, serde_json::Value>,
+    pub source_file: Option<String>,
+}
+
+impl Candidate {
+    pub fn new(data: &NormalizeData) -> Self {
+        let scores: BTreeMap<String, serde_json::Value> = data.scores.iter()
+            .filter(|score| !score.derived)
+            .map(|score| (score.dimension.clone(), serde_json::json!(score.value)))
+            .collect();
+        // The date of an assessment without a source date is the processing time, which differs
+        // every time the same assessment is processed.
+        let assessment_time = DateTime::parse_from_rfc3339(&data.assessmentDate).ok()
+            .map(|date| date.to_utc())
+            .filter(|_| !data.date_unknown);
+        let date = match assessment_time {
+            Some(time) => Some(time.to_rfc3339()),
+            None if data.date_unknown => None,
+            None => Some(data.assessmentDate.clone()),
+        };
+        let canonical = serde_json::json!([data.patientId, data.assessmentType, date, scores]);
+        let digest = Sha256::digest(canonical.to_string().as_bytes());
+        Self {
+            fingerprint: digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            assessment_time,
+            scores,
+            source_file: data.metadata.get("sourceFile").cloned(),
+        }
+    }
+}
+
+impl DedupConfig {
+    /// Whether `candidate` duplicates `earlier`, both of the same patient and type. The dates are
+    /// only compared when both assessments have one.
+    pub fn compare(&self, candidate: &Candidate, earlier: &Candidate) -> Option<DuplicateKind> {
+        if candidate.fingerprint == earlier.fingerprint {
+            return Some(DuplicateKind::Exact);
+        }
+
+        if let (Some(time), Some(earlier_time)) = (candidate.assessment_time, earlier.assessment_time)
+            && (time - earlier_time).num_hours().abs() > self.window_hours {
+            return None;
+        }
+        let dimensions = candidate.scores.keys().chain(earlier.scores.keys().filter(|key| !candidate.scores.contains_key(*key))).count();
+        let equal = candidate.scores.iter().filter(|(key, value)| earlier.scores.get(*key) == Some(value)).count();
+        (dimensions > 0 && equal as f64 / dimensions as f64 >= self.similarity).then_some(DuplicateKind::Near)
+    }
+
+    /// Fingerprints the assessments of one file and handles those duplicating an earlier
+    /// assessment of the file or a stored one of another file. Every assessment gets a
+    /// `fingerprint` metadata entry, and amendments and retractions are never duplicates.
+    pub fn deduplicate(&self, store: &Store, normalized: &mut Vec<NormalizeData>) -> Result<Vec<DuplicateIssue>, NormalizationError> {
+        let mut issues = Vec::new();
+        let mut kept: Vec<(Candidate, NormalizeData)> = Vec::new();
+        for (record, mut data) in normalized.drain(..).enumerate() {
+            let candidate = Candidate::new(&data);
+            data.metadata.insert("fingerprint".to_string(), candidate.fingerprint.clone());
+            // Amendments and retractions are new versions of a record rather than duplicates.
+            if !data.status.is_original() {
+                kept.push((candidate, data));
+                continue;
+            }
+
+            let mut earlier: Vec<Candidate> = kept.iter()
+                .filter(|(_, kept)| kept.patientId == data.patientId && kept.assessmentType == data.assessmentType)
+                .map(|(candidate, _)| candidate.clone())
+                .collect();
+            earlier.extend(store.candidates(&data)?);
+            let duplicate = [DuplicateKind::Exact, DuplicateKind::Near].into_iter()
+                .find_map(|kind| earlier.iter().find(|earlier| self.compare(&candidate, earlier) == Some(kind)).map(|earlier| (kind, earlier)));
+
+            if let Some((kind, earlier)) = duplicate {
+                issues.push(DuplicateIssue {
+                    record,
+                    kind,
+                    patient_id: data.patientId.clone(),
+                    assessment_type: data.assessmentType.clone(),
+                    duplicate_of: earlier.fingerprint.clone(),
+                    source_file: earlier.source_file.clone(),
+                });
+                match self.policy {
+                    DedupPolicy::Drop => continue,
+                    DedupPolicy::Flag => {
+                        data.metadata.insert("duplicate".to_string(), if kind == DuplicateKind::Exact {"exact"} else {"near"}.to_string());
+                        data.metadata.insert("duplicateOf".to_string(), earlier.fingerprint.clone());
+                    },
+                    DedupPolicy::Supersede => {
+                        data.supersedes = Some(earlier.fingerprint.clone());
+                        let fingerprint = earlier.fingerprint.clone();
+                        kept.retain(|(kept, _)| kept.fingerprint != fingerprint);
+                    },
+                }
+            }
+            kept.push((candidate, data));
+        }
+
+        normalized.extend(kept.into_iter().map(|(_, data)| data));
+        Ok(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{assessment, config, normalize};
+
+    /// An extract of item scores `q1`, `q2`, ... of P1 from `file`, at an hour of 2024-10-15.
+    fn extract(file: &str, hour: u32, values: &[i64]) -> NormalizeData {
+        let items: Vec<String> = (1..=values.len()).map(|item| format!("q{}", item)).collect();
+        let scores: Vec<(&str, i64)> = items.iter().map(String::as_str).zip(values.iter().copied()).collect();
+        let mut data = assessment("P1", "cognitive", &format!("2024-10-15T{:02}:00:00Z", hour), &scores);
+        data.metadata.insert("provider".to_string(), "c".to_string());
+        data.metadata.insert("sourceFile".to_string(), file.to_string());
+        data
+    }
+
+    #[test]
+    fn dedup_test() {
+        let mut store = Store::open(":memory:").unwrap();
+        let mut first = vec![extract("monday.c", 9, &[1, 2, 3, 0, 1])];
+        let config = DedupConfig::default();
+        assert!(config.deduplicate(&store, &mut first).unwrap().is_empty());
+        store.insert(&first).unwrap();
+
+        // The same extract resent under another name, and a near duplicate with one value changed.
+        let mut resent = vec![extract("tuesday.c", 9, &[1, 2, 3, 0, 1]), extract("tuesday.c", 12, &[1, 2, 3, 0, 2])];
+        let issues = config.deduplicate(&store, &mut resent.clone()).unwrap();
+        assert_eq!(issues.iter().map(|issue| issue.kind).collect::<Vec<_>>(), vec![DuplicateKind::Exact, DuplicateKind::Near]);
+        assert_eq!(issues[0].duplicate_of, first[0].metadata["fingerprint"]);
+        assert_eq!(issues[0].source_file.as_deref(), Some("monday.c"));
+
+        let drop = DedupConfig {policy: DedupPolicy::Drop, ..DedupConfig::default()};
+        drop.deduplicate(&store, &mut resent).unwrap();
+        assert!(resent.is_empty());
+
+        // Reprocessing the same file is not a duplicate, since it replaces what was stored from it.
+        let mut again = vec![extract("monday.c", 9, &[1, 2, 3, 0, 1])];
+        assert!(drop.deduplicate(&store, &mut again).unwrap().is_empty());
+        assert!(drop.compare(&Candidate::new(&extract("a.c", 9, &[1, 2])), &Candidate::new(&extract("b.c", 12, &[0, 0]))).is_none());
+    }
+
+    #[test]
+    fn undated_dedup_test() {
+        let (config, dedup) = (config(), DedupConfig::default());
+        let mut store = Store::open(":memory:").unwrap();
+        let mut first = normalize(&config, "data.a");
+        assert!(dedup.deduplicate(&store, &mut first).unwrap().is_empty());
+        store.insert(&first).unwrap();
+
+        // Provider A gives no dates, so the same file resent later is still an exact duplicate.
+        let mut resent = normalize(&config, "data.a");
+        resent[0].metadata.insert("sourceFile".to_string(), "resent.a".to_string());
+        assert!(resent[0].date_unknown);
+        let issues = dedup.deduplicate(&store, &mut resent).unwrap();
+        assert_eq!(issues.iter().map(|issue| issue.kind).collect::<Vec<_>>(), vec![DuplicateKind::Exact]);
+        assert_eq!(issues[0].duplicate_of, first[0].metadata["fingerprint"]);
+
+        // Without dates, one changed value of five is a near duplicate however far apart they were processed.
+        let mut earlier = extract("a.a", 9, &[1, 2, 3, 0, 1]);
+        earlier.date_unknown = true;
+        let mut later = extract("b.a", 9, &[1, 2, 3, 0, 2]);
+        later.assessmentDate = "2025-01-01T00:00:00+00:00".into();
+        assert_eq!(dedup.compare(&Candidate::new(&later), &Candidate::new(&earlier)), Some(DuplicateKind::Near));
+    }
+}
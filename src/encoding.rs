@@ -0,0 +1,64 @@
+use encoding_rs::{DecoderResult, Encoding, UTF_8};
+
+use crate::model::NormalizationError;
+
+/// Most undecodable byte offsets listed in a single error.
+const MAX_REPORTED_OFFSETS: usize = 20;
+
+/// Resolves the encoding of an input, preferring a byte order mark over the configured label.
+/// Returns the encoding and the length of the BOM to skip.
+pub fn detect(bytes: &[u8], label: Option<&str>) -> Result<(&'static Encoding, usize), NormalizationError> {
+    if let Some(detected) = Encoding::for_bom(bytes) {
+        return Ok(detected);
+    }
+
+    match label {
+        Some(label) => Encoding::for_label(label.trim().as_bytes())
+            .map(|encoding| (encoding, 0))
+            .ok_or_else(|| NormalizationError::Unknown(format!("Unknown encoding: {}", label))),
+        None => Ok((UTF_8, 0)),
+    }
+}
+
+/// Transcodes raw input bytes to UTF-8. Undecodable bytes fail the whole input and are
+/// reported by their offset in `bytes`.
+pub fn decode(bytes: &[u8], label: Option<&str>) -> Result<String, NormalizationError> {
+    let (encoding, bom_length) = detect(bytes, label)?;
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut output = String::with_capacity(
+        decoder.max_utf8_buffer_length_without_replacement(bytes.len()).unwrap_or(bytes.len()));
+    let mut offsets = Vec::new();
+    let mut position = bom_length;
+    loop {
+        let (result, read) = decoder.decode_to_string_without_replacement(&bytes[position..], &mut output, true);
+        position += read;
+        match result {
+            DecoderResult::InputEmpty => break,
+            DecoderResult::OutputFull => output.reserve(bytes.len() - position + 16),
+            DecoderResult::Malformed(bad, extra) => {
+                offsets.push(position - bad as usize - extra as usize);
+            },
+        }
+    }
+
+    if offsets.is_empty() {
+        Ok(output)
+    } else {
+        offsets.truncate(MAX_REPORTED_OFFSETS);
+        Err(NormalizationError::Decode(encoding.name().to_string(), offsets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(decode(b"\xEF\xBB\xBFpatient_id", None).unwrap(), "patient_id");
+        assert_eq!(decode(b"\xFF\xFEi\x00d\x00", None).unwrap(), "id");
+        assert_eq!(decode(b"caf\xE9", Some("latin1")).unwrap(), "caf\u{e9}");
+        assert_eq!(decode(b"ok\xFFok\xFE", None),
+            Err(NormalizationError::Decode("UTF-8".into(), vec![2, 5])));
+    }
+}
@@ -0,0 +1,263 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeSet;
+
+use crate::config::Config;
+use crate::dedup::{Candidate, DedupPolicy, DuplicateIssue, DuplicateKind};
+use crate::input::InputFile;
+use crate::model::{NormalizationError, NormalizeData, NormalizeScore, ScoreValue};
+use crate::ranges::RangeStage;
+use crate::store::{Store, serde_name};
+
+/// The steps of the derivation of a score, in the order they happen.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// The value as written in the input.
+    Raw,
+    Validation,
+    /// The patient, assessment and dimension the value was filed under.
+    Mapping,
+    /// Conversion of the raw value, e.g. from 0-10 onto 0-100.
+    Scaling,
+    /// Computation of a derived score from other scores.
+    Derivation,
+    /// How the score joined its assessment, and what deduplication and versioning made of it.
+    Merge,
+    Interpretation,
+    Final,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Step {
+    pub stage: Stage,
+    pub detail: String,
+}
+
+/// How one output score was produced.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScoreExplanation {
+    pub patient_id: String,
+    pub assessment_type: String,
+    pub dimension: String,
+    #[serde(flatten)]
+    pub value: ScoreValue,
+    pub steps: Vec<Step>,
+}
+
+/// Explanations of the scores of one input file.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Explanation {
+    pub file: String,
+    pub scores: Vec<ScoreExplanation>,
+    /// Issues not tied to an output score, such as records rejected by validation.
+    pub issues: Vec<NormalizationError>,
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.file)?;
+        for score in &self.scores {
+            writeln!(f, "\n{} {} {} = {}", score.patient_id, score.assessment_type, score.dimension, score.value)?;
+            for (index, step) in score.steps.iter().enumerate() {
+                writeln!(f, "  {}. {}: {}", index + 1, serde_name(&step.stage).unwrap_or_default(), step.detail)?;
+            }
+        }
+        if !self.issues.is_empty() {
+            writeln!(f, "\nIssues without an output score:")?;
+            for issue in &self.issues {
+                writeln!(f, "  {}", serde_json::json!(issue))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds the issues inside `errors` to `output`.
+fn flatten(errors: NormalizationError, output: &mut Vec<NormalizationError>) {
+    match errors {
+        NormalizationError::None => {},
+        NormalizationError::Aggregate(errors) => errors.into_iter().for_each(|errors| flatten(errors, output)),
+        err => output.push(err),
+    }
+}
+
+/// Whether an issue concerns a score of the normalized assessment at `record`.
+fn concerns(issue: &NormalizationError, record: usize, dimension: &str) -> bool {
+    match issue {
+        NormalizationError::Range(issue) => issue.stage == RangeStage::Target && issue.record == record && issue.dimension == dimension,
+        NormalizationError::Norm(issue) => issue.record == record && issue.dimension == dimension,
+        _ => false,
+    }
+}
+
+/// Normalizes an input file the way the watcher does, but without writing any output, history or
+/// database rows, and explains every score of the given patient and dimension, or every score.
+pub fn explain(
+    config: &Config,
+    store: &Store,
+    input: &InputFile,
+    patient: Option<&str>,
+    dimension: Option<&str>) -> Result<Explanation, NormalizationError> {
+    let (normalized, errors) = crate::handle_data(config, input)?;
+    let mut deduplicated = normalized.clone();
+    let duplicates = config.dedup.deduplicate(store, &mut deduplicated)?;
+    store.assign_versions(&mut deduplicated)?;
+
+    let mut issues = Vec::new();
+    flatten(errors, &mut issues);
+    let mut scores = Vec::new();
+    for (record, data) in normalized.iter().enumerate() {
+        if patient.is_some_and(|patient| patient != data.patientId) {
+            continue;
+        }
+        let merge = merge_detail(config.dedup.policy, record, data, &duplicates, &deduplicated);
+        for score in data.scores.iter().filter(|score| dimension.is_none_or(|dimension| dimension == score.dimension)) {
+            let mut steps = source_steps(data, score);
+            let score_issues: Vec<String> = issues.iter()
+                .filter(|issue| concerns(issue, record, &score.dimension))
+                .map(|issue| match issue {
+                    NormalizationError::Range(issue) => format!("{} is outside the target range {} and was kept", issue.value, issue.range),
+                    NormalizationError::Norm(issue) => format!("not normed, as the {}", issue.reason),
+                    issue => serde_json::json!(issue).to_string(),
+                })
+                .collect();
+            let validated = match score.lineage.as_ref().and_then(|lineage| lineage.record) {
+                Some(source) => format!("record {} passed validation", source),
+                None => "passed validation".to_string(),
+            };
+            steps.insert(steps.len().min(1), Step {
+                stage: Stage::Validation,
+                detail: [vec![validated], score_issues].concat().join("; "),
+            });
+            steps.push(Step {stage: Stage::Merge, detail: merge.clone()});
+            if let Some(interpretation) = score.interpretation {
+                steps.push(Step {
+                    stage: Stage::Interpretation,
+                    detail: format!("{} by the severity cut-offs of {}", serde_name(&interpretation).unwrap_or_default(), data.assessmentType),
+                });
+            }
+            steps.push(Step {stage: Stage::Final, detail: format!("{} on scale {}", score.value, score.scale)});
+            scores.push(ScoreExplanation {
+                patient_id: data.patientId.clone(),
+                assessment_type: data.assessmentType.clone(),
+                dimension: score.dimension.clone(),
+                value: score.value.clone(),
+                steps,
+            });
+        }
+    }
+
+    issues.retain(|issue| !matches!(issue, NormalizationError::Range(issue) if issue.stage == RangeStage::Target) &&
+        !matches!(issue, NormalizationError::Norm(_)));
+    Ok(Explanation {file: input.output_name(), scores, issues})
+}
+
+/// The raw, mapping and scaling steps of a source score, or the derivation of a derived one.
+fn source_steps(data: &NormalizeData, score: &NormalizeScore) -> Vec<Step> {
+    let Some(lineage) = &score.lineage else {
+        return vec![Step {stage: Stage::Raw, detail: "no lineage recorded".into()}];
+    };
+    if score.derived {
+        return vec![Step {stage: Stage::Derivation, detail: format!("{} from {}", lineage.transform, lineage.field)}];
+    }
+
+    let raw = lineage.raw.clone().unwrap_or_default();
+    let mut location = Vec::new();
+    if let Some(record) = lineage.record {
+        location.push(format!("record {}", record));
+    }
+    if let Some(line) = lineage.line {
+        location.push(format!("line {}", line));
+    }
+    if let Some(source_file) = &lineage.source_file {
+        location.push(format!("of {}", source_file));
+    }
+    if let Some(checksum) = &lineage.checksum {
+        location.push(format!("(sha256 {})", checksum));
+    }
+    vec![
+        Step {stage: Stage::Raw, detail: format!("{} = {}, {}", lineage.field, raw, location.join(" "))},
+        Step {
+            stage: Stage::Mapping,
+            detail: format!("{} is {} of {} for patient {}", lineage.field, score.dimension, data.assessmentType, data.patientId),
+        },
+        Step {stage: Stage::Scaling, detail: format!("{}: {} -> {}", lineage.transform, raw, score.value)},
+    ]
+}
+
+/// How the assessment at `record` was put together, and whether it would be kept, flagged, dropped
+/// or stored as a new version.
+fn merge_detail(
+    policy: DedupPolicy,
+    record: usize,
+    data: &NormalizeData,
+    duplicates: &[DuplicateIssue],
+    deduplicated: &[NormalizeData]) -> String {
+    let records: BTreeSet<usize> = data.scores.iter()
+        .filter(|score| !score.derived)
+        .filter_map(|score| score.lineage.as_ref().and_then(|lineage| lineage.record))
+        .collect();
+    let mut details = vec![match records.len() {
+        0 | 1 => format!("one {} assessment of {}", data.assessmentType, data.assessmentDate),
+        _ => format!("records {} merged into one {} assessment of {}",
+            records.iter().map(|record| record.to_string()).collect::<Vec<_>>().join(", "), data.assessmentType, data.assessmentDate),
+    }];
+    if let Some(record_id) = &data.record_id {
+        details.push(format!("record id {}, {}", record_id, serde_name(&data.status).unwrap_or_default()));
+    } else if !data.status.is_original() {
+        details.push(serde_name(&data.status).unwrap_or_default());
+    }
+
+    let duplicate = duplicates.iter().find(|duplicate| duplicate.record == record);
+    if let Some(duplicate) = duplicate {
+        let kind = if duplicate.kind == DuplicateKind::Exact {"an exact"} else {"a near"};
+        let of = format!("{} duplicate of {} from {}", kind, duplicate.duplicate_of, duplicate.source_file.as_deref().unwrap_or("this file"));
+        details.push(match policy {
+            DedupPolicy::Drop => format!("dropped as {}", of),
+            DedupPolicy::Flag => format!("kept and flagged as {}", of),
+            DedupPolicy::Supersede => format!("kept as {}, which it supersedes", of),
+        });
+    }
+    if duplicate.is_some() && policy == DedupPolicy::Drop {
+        return details.join("; ");
+    }
+
+    let fingerprint = Candidate::new(data).fingerprint;
+    match deduplicated.iter().find(|kept| kept.metadata.get("fingerprint") == Some(&fingerprint)) {
+        Some(kept) => details.push(match &kept.supersedes {
+            Some(supersedes) => format!("stored as version {}, replacing {}", kept.version, supersedes),
+            None => format!("stored as version {}", kept.version),
+        }),
+        None => details.push("superseded by a later duplicate in the same file".into()),
+    }
+
+    details.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explain_test() {
+        let config = Config::default();
+        let store = Store::open(":memory:").unwrap();
+        let input = InputFile {
+            name: "data.a".into(),
+            archive: None,
+            bytes: include_bytes!("../test-data/data.a").to_vec(),
+        };
+
+        let explanation = explain(&config, &store, &input, Some("P123a"), Some("anxiety")).unwrap();
+        assert_eq!(explanation.scores.len(), 1);
+        let score = &explanation.scores[0];
+        assert_eq!(score.value, ScoreValue::Integer(70));
+        let stages: Vec<Stage> = score.steps.iter().map(|step| step.stage).collect();
+        assert_eq!(stages, vec![Stage::Raw, Stage::Validation, Stage::Mapping, Stage::Scaling, Stage::Merge, Stage::Final]);
+        assert_eq!(score.steps[3].detail, "x10: 7 -> 70");
+        assert!(score.steps[4].detail.starts_with("one behavioral_screening assessment of "));
+        assert!(score.steps[4].detail.ends_with("; stored as version 1"));
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+use crate::model::NormalizationError;
+
+/// How deep archives may be nested, e.g. `.tar.gz` uses two levels.
+const MAX_DEPTH: usize = 4;
+
+/// Limits applied when expanding compressed and archived inputs.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct ArchiveLimits {
+    /// Most bytes all members of one input file may expand to.
+    pub max_total_bytes: u64,
+    /// Most bytes expanded per byte of the input file.
+    pub max_ratio: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: 256 * 1024 * 1024,
+            max_ratio: 100,
+        }
+    }
+}
+
+/// A single file to hand to a provider, either read directly or expanded from an archive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputFile {
+    /// File name, or the member path inside `archive`.
+    pub name: String,
+    pub archive: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+impl InputFile {
+    pub fn extension(&self) -> Option<&str> {
+        Path::new(&self.name).extension().and_then(|extension| extension.to_str())
+    }
+
+    /// SHA-256 of the contents, in hex.
+    pub fn checksum(&self) -> String {
+        Sha256::digest(&self.bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Metadata recording where the file came from.
+    pub fn lineage(&self) -> BTreeMap<String, String> {
+        let mut lineage = BTreeMap::from([
+            ("sourceFile".to_string(), self.name.clone()),
+            ("sourceChecksum".to_string(), self.checksum()),
+        ]);
+        if let Some(archive) = &self.archive {
+            lineage.insert("sourceArchive".to_string(), archive.clone());
+        }
+
+        lineage
+    }
+
+    /// Name used for the normalized output of this file.
+    pub fn output_name(&self) -> String {
+        match &self.archive {
+            Some(archive) => format!("{}_{}", archive, self.name.replace(['/', '\\'], "_")),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Splits an input file into the files inside it. Files that are not compressed or
+/// archived are returned as they are.
+pub fn expand(file_name: &str, bytes: Vec<u8>, limits: &ArchiveLimits) -> Result<Vec<InputFile>, NormalizationError> {
+    if archive_kind(file_name).is_none() {
+        return Ok(vec![InputFile { name: file_name.to_string(), archive: None, bytes }]);
+    }
+
+    let mut budget = limits.max_total_bytes.min((bytes.len() as u64).saturating_mul(limits.max_ratio));
+    let mut output = Vec::new();
+    expand_into(file_name, None, bytes, &mut budget, MAX_DEPTH, &mut output)
+        .map_err(|err| NormalizationError::Parse(format!("{}: {}", file_name, err)))?;
+    for input in output.iter_mut() {
+        input.archive = Some(file_name.to_string());
+    }
+
+    Ok(output)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ArchiveKind {
+    Gzip,
+    Zip,
+    Tar,
+}
+
+fn archive_kind(name: &str) -> Option<ArchiveKind> {
+    let name = name.to_lowercase();
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::Gzip)
+    } else if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else {
+        None
+    }
+}
+
+/// Expands `bytes` into `output`. `member` is the path of `bytes` inside the outermost
+/// archive, or `None` for the outermost archive itself.
+fn expand_into(
+    name: &str,
+    member: Option<&str>,
+    bytes: Vec<u8>,
+    budget: &mut u64,
+    depth: usize,
+    output: &mut Vec<InputFile>,
+) -> Result<(), String> {
+    let kind = match archive_kind(name) {
+        Some(kind) => kind,
+        None => {
+            output.push(InputFile { name: member.unwrap_or(name).to_string(), archive: None, bytes });
+            return Ok(());
+        }
+    };
+    if depth == 0 {
+        return Err(format!("archives nested more than {} levels deep", MAX_DEPTH));
+    }
+
+    let child_name = |entry: &str| match member {
+        Some(member) => format!("{}/{}", member, entry),
+        None => entry.to_string(),
+    };
+    match kind {
+        ArchiveKind::Gzip => {
+            let contents = read_limited(flate2::read::GzDecoder::new(Cursor::new(bytes)), budget)?;
+            let inner_member = gzip_inner_name(member.unwrap_or(name));
+            expand_into(&gzip_inner_name(name), Some(&inner_member), contents, budget, depth - 1, output)?;
+        },
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|err| err.to_string())?;
+            for index in 0..archive.len() {
+                let entry = archive.by_index(index).map_err(|err| err.to_string())?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let entry_name = entry.name().to_string();
+                let contents = read_limited(entry, budget)?;
+                let entry_member = child_name(&entry_name);
+                expand_into(&entry_name, Some(&entry_member), contents, budget, depth - 1, output)?;
+            }
+        },
+        ArchiveKind::Tar => {
+            let mut archive = tar::Archive::new(Cursor::new(bytes));
+            for entry in archive.entries().map_err(|err| err.to_string())? {
+                let entry = entry.map_err(|err| err.to_string())?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let entry_name = entry.path().map_err(|err| err.to_string())?.to_string_lossy().to_string();
+                let contents = read_limited(entry, budget)?;
+                let entry_member = child_name(&entry_name);
+                expand_into(&entry_name, Some(&entry_member), contents, budget, depth - 1, output)?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Name of the file inside a gzip stream: `data.c.gz` -> `data.c`, `data.tgz` -> `data.tar`.
+fn gzip_inner_name(name: &str) -> String {
+    let lower = name.to_lowercase();
+    if lower.ends_with(".tgz") {
+        format!("{}.tar", &name[..name.len() - ".tgz".len()])
+    } else {
+        name[..name.len() - ".gz".len()].to_string()
+    }
+}
+
+/// Reads a decompressing reader, failing once more than `budget` bytes come out of it.
+fn read_limited(reader: impl Read, budget: &mut u64) -> Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    reader.take(*budget + 1).read_to_end(&mut contents).map_err(|err| err.to_string())?;
+    if contents.len() as u64 > *budget {
+        return Err("expanded size exceeds the archive limits".into());
+    }
+
+    *budget -= contents.len() as u64;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn expand_archive_test() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        writer.start_file("clinic/data.a", options).unwrap();
+        writer.write_all(b"[]").unwrap();
+        writer.start_file("data.c.gz", options).unwrap();
+        writer.write_all(&gzip(b"patient_id")).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        let inputs = expand("bundle.zip", bytes, &ArchiveLimits::default()).unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].name, "clinic/data.a");
+        assert_eq!(inputs[0].output_name(), "bundle.zip_clinic_data.a");
+        assert_eq!(inputs[1].name, "data.c");
+        assert_eq!(inputs[1].extension(), Some("c"));
+        assert_eq!(inputs[1].bytes, b"patient_id");
+        assert_eq!(inputs[1].lineage()["sourceArchive"], "bundle.zip");
+    }
+
+    #[test]
+    fn expand_limits_test() {
+        let bytes = gzip(&vec![b'0'; 100_000]);
+        let limits = ArchiveLimits { max_total_bytes: 1_000_000, max_ratio: 10 };
+        assert!(expand("data.c.gz", bytes.clone(), &limits).is_err());
+
+        let limits = ArchiveLimits { max_total_bytes: 1_000_000, max_ratio: 1_000 };
+        let inputs = expand("data.c.gz", bytes, &limits).unwrap();
+        assert_eq!(inputs[0].name, "data.c");
+        assert_eq!(inputs[0].bytes.len(), 100_000);
+    }
+}
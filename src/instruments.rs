@@ -0,0 +1,170 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+use crate::config::load_json_dir;
+use crate::model::{NormalizationError, NormalizeData};
+use crate::ranges::{Range, RangeCatalog, RangeEntry};
+use crate::scoring::Scoring;
+use crate::severity::{self, SeverityRule};
+use crate::timeline::ReliableChange;
+
+/// One expected score of an instrument.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct InstrumentDimension {
+    pub name: String,
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// Valid normalized values.
+    #[serde(default)]
+    pub range: Option<Range>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+/// What a complete assessment of one type looks like.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Instrument {
+    pub assessment_type: String,
+    #[serde(default)]
+    pub version: Option<String>,
+    pub dimensions: Vec<InstrumentDimension>,
+    /// Derived scores computed from the item scores.
+    #[serde(default)]
+    pub scoring: Option<Scoring>,
+    /// Cut-offs of source, derived or normed dimensions.
+    #[serde(default)]
+    pub severity: Vec<SeverityRule>,
+    /// Reliabilities of the dimensions whose changes over time are flagged.
+    #[serde(default)]
+    pub reliable_change: Vec<ReliableChange>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompletenessStatus {
+    Complete,
+    Partial,
+}
+
+/// How an assessment compares with its instrument. Dimensions count as missing when they have no
+/// score or only a missing value.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Completeness {
+    pub status: CompletenessStatus,
+    pub instrument_version: Option<String>,
+    pub missing_required: Vec<String>,
+    pub missing_optional: Vec<String>,
+    /// Source scores the instrument does not list.
+    pub unexpected: Vec<String>,
+}
+
+/// Instruments keyed by assessment type, maintained as one JSON file per instrument.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstrumentCatalog(pub BTreeMap<String, Instrument>);
+
+impl InstrumentCatalog {
+    /// Loads every `.json` file in a directory, which need not exist.
+    pub fn load_dir(path: &str) -> Result<Self, NormalizationError> {
+        let mut catalog = Self::default();
+        for (name, instrument) in load_json_dir::<Instrument>(path)? {
+            if let Some(scoring) = &instrument.scoring {
+                scoring.validate().map_err(|err| NormalizationError::Parse(format!("{}: {}", name, err)))?;
+            }
+            for rule in &instrument.severity {
+                rule.validate().map_err(|err| NormalizationError::Parse(format!("{}: {}", name, err)))?;
+            }
+            for reliable_change in &instrument.reliable_change {
+                reliable_change.validate().map_err(|err| NormalizationError::Parse(format!("{}: {}", name, err)))?;
+            }
+            catalog.0.insert(instrument.assessment_type.clone(), instrument);
+        }
+
+        Ok(catalog)
+    }
+
+    /// Target ranges of every instrument dimension that has one.
+    pub fn ranges(&self) -> RangeCatalog {
+        RangeCatalog(self.0.values()
+            .flat_map(|instrument| instrument.dimensions.iter().map(|dimension| RangeEntry {
+                assessment_type: instrument.assessment_type.clone(),
+                dimension: dimension.name.clone(),
+                source: None,
+                target: dimension.range,
+            }))
+            .filter(|entry| entry.target.is_some())
+            .collect())
+    }
+
+    /// Adds the derived scores of an assessment whose instrument has scoring rules. See
+    /// `Scoring::score` for `ranges` and `source_range`.
+    pub fn score(&self, data: &mut NormalizeData, ranges: &RangeCatalog, source_range: Option<Range>) {
+        if let Some(scoring) = self.0.get(&data.assessmentType).and_then(|instrument| instrument.scoring.as_ref()) {
+            scoring.score(data, ranges, source_range);
+        }
+    }
+
+    /// Interprets the scores of an assessment against its instrument's cut-offs.
+    pub fn interpret(&self, data: &mut NormalizeData) {
+        if let Some(instrument) = self.0.get(&data.assessmentType) {
+            severity::interpret(&instrument.severity, data);
+        }
+    }
+
+    pub fn reliable_change(&self, assessment_type: &str, dimension: &str) -> Option<&ReliableChange> {
+        self.0.get(assessment_type)?.reliable_change.iter().find(|reliable_change| reliable_change.dimension == dimension)
+    }
+
+    /// Compares an assessment with its instrument, or None for assessment types not in the catalogue.
+    pub fn completeness(&self, data: &NormalizeData) -> Option<Completeness> {
+        let instrument = self.0.get(&data.assessmentType)?;
+        let answered = |name: &str| data.scores.iter()
+            .any(|score| score.dimension == name && !score.value.is_missing());
+        let missing = |required: bool| instrument.dimensions.iter()
+            .filter(|dimension| dimension.required == required && !answered(&dimension.name))
+            .map(|dimension| dimension.name.clone())
+            .collect::<Vec<_>>();
+
+        let missing_required = missing(true);
+        Some(Completeness {
+            status: if missing_required.is_empty() {CompletenessStatus::Complete} else {CompletenessStatus::Partial},
+            instrument_version: instrument.version.clone(),
+            missing_required,
+            missing_optional: missing(false),
+            unexpected: data.scores.iter()
+                .filter(|score| !score.derived &&
+                    !instrument.dimensions.iter().any(|dimension| dimension.name == score.dimension))
+                .map(|score| score.dimension.clone())
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use crate::model::{MissingReason, NormalizeScore, ScoreValue};
+
+    #[test]
+    fn completeness_test() {
+        let catalog = InstrumentCatalog::load_dir("instruments").unwrap();
+        assert!(catalog.0.contains_key("behavioral_screening"));
+        assert_eq!(catalog.ranges().0.len(), 5);
+
+        let score = |dimension: &str, value| NormalizeScore {dimension: dimension.into(), value, scale: "0-100".into(), derived: false, interpretation: None, lineage: None};
+        let mut data = NormalizeData::new("P1".into(), "cognitive".into(), Utc::now(), BTreeMap::new());
+        data.scores.push(score("memory", ScoreValue::Integer(85)));
+        data.scores.push(score("processing", ScoreValue::Missing(MissingReason::Refused)));
+        data.scores.push(score("speed", ScoreValue::Integer(40)));
+        let completeness = catalog.completeness(&data).unwrap();
+        assert_eq!(completeness.status, CompletenessStatus::Partial);
+        assert_eq!(completeness.missing_required, vec!["processing".to_string()]);
+        assert_eq!(completeness.unexpected, vec!["speed".to_string()]);
+
+        data.assessmentType = "unknown".into();
+        assert!(catalog.completeness(&data).is_none());
+    }
+}
@@ -1,106 +1,380 @@
+mod backfill;
+mod config;
+mod dedup;
+mod encoding;
+mod explain;
+mod input;
+mod instruments;
 mod model;
+mod norms;
 mod provider_a;
 mod provider_b;
 mod provider_c;
+mod provider_fhir;
+mod provider_hl7;
+mod provider_xlsx;
+mod provider_xml;
+mod ranges;
+mod scoring;
+mod severity;
+mod store;
+#[cfg(test)]
+mod testing;
+mod timeline;
 
-use std::{collections::BTreeMap, io::Read, time::Duration};
+use std::time::Duration;
 
-use crate::model::{NormalizationError, NormalizeData, Provider};
+use crate::backfill::{BackfillRange, OutputStatus};
+use crate::config::Config;
+use crate::input::InputFile;
+use crate::model::{NormalizationError, NormalizeData, Provider, RecordStatus};
+use crate::store::{ScoreQuery, Store};
 
 pub fn run_provider(
-    data: &str, 
+    data: &[u8],
+    encoding: Option<&str>,
     provider: &mut dyn Provider) -> Result<(Vec<NormalizeData>, NormalizationError), NormalizationError> {
-    if let Err(err) = provider.parse(data) {
-        return Err(err);
-    }
+    provider.parse_bytes(data, encoding)?;
     let valdation_errors = provider.validate();
     Ok((provider.convert(), valdation_errors))
 }
 
-fn read_file_contents(file_path: &str) -> String {
-    let mut output: String = "".into();
-    match std::fs::File::open(file_path) {
-        Ok(mut f) => {
-            let _ = f.read_to_string(&mut output);
-        },
-        Err(err) => {
-            println!("error reading file: {:?}", err);
-        }
-    }
-
-    output
-}
-
 pub fn handle_data(
-    provider_name: &str,
-    file_path: &str, 
+    config: &Config,
+    input: &InputFile,
 ) -> Result<(Vec<NormalizeData>, NormalizationError), NormalizationError> {
-    match provider_name {
+    let file_type = input.extension()
+        .ok_or_else(|| NormalizationError::Unknown(format!("File has no extension: {}", input.name)))?;
+    let (provider_name, source) = config.source(file_type);
+    let encoding = source.encoding.clone();
+    let ranges = source.ranges.clone();
+    let data = &input.bytes;
+    let (mut normalized, errors) = match provider_name.as_str() {
         "a" => {
-            let mut handler = provider_a::ProviderHandler::new();
-            let data = read_file_contents(file_path);
-            return run_provider(&data, &mut handler as &mut dyn Provider);
+            let mut handler = provider_a::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
         },
         "b" => {
-            let mut handler = provider_b::ProviderHandler::new();
-            let data = read_file_contents(file_path);
-            return run_provider(&data, &mut handler as &mut dyn Provider);
+            let mut handler = provider_b::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
         },
         "c" => {
-            let mut handler = provider_c::ProviderHandler::new();
-            let data = read_file_contents(file_path);
-            return run_provider(&data, &mut handler as &mut dyn Provider);
+            let mut handler = provider_c::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
+        },
+        "fhir" => {
+            let mut handler = provider_fhir::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
+        },
+        "hl7" => {
+            let mut handler = provider_hl7::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
         },
-        _ => {
-            return Err(NormalizationError::Unknown(format!("Provider not found with name: {}", provider_name)));
+        "xml" => {
+            let mut handler = provider_xml::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
+        },
+        "xlsx" => {
+            let mut handler = provider_xlsx::ProviderHandler::with_source(source);
+            run_provider(data, encoding.as_deref(), &mut handler as &mut dyn Provider)
+        },
+        _ => Err(NormalizationError::Unknown(format!("Provider not found with name: {}", provider_name))),
+    }?;
+
+    // Providers that scale their values onto the target ranges; item ranges are scaled to match.
+    let source_range = match provider_name.as_str() {
+        "a" => Some(provider_a::SOURCE_RANGE),
+        "c" | "xlsx" => Some(provider_c::SOURCE_RANGE),
+        _ => None,
+    };
+    let mut issues = Vec::new();
+    let lineage = input.lineage();
+    for (index, data) in normalized.iter_mut().enumerate() {
+        data.metadata.extend(lineage.clone());
+        data.metadata.insert("provider".to_string(), provider_name.clone());
+        config.instruments.score(data, &ranges, source_range);
+        issues.extend(config.norms.apply(index, data).into_iter().map(NormalizationError::Norm));
+        config.instruments.interpret(data);
+        data.completeness = config.instruments.completeness(data);
+        for score_lineage in data.scores.iter_mut().filter_map(|score| score.lineage.as_mut()) {
+            score_lineage.source_file = Some(input.name.clone());
+            score_lineage.checksum = lineage.get("sourceChecksum").cloned();
         }
     }
+
+    // Normalized values outside their target range are reported but kept.
+    issues.extend(ranges.check_normalized(&normalized).into_iter().map(NormalizationError::Range));
+    Ok((normalized, with_issues(errors, issues)))
+}
+
+/// Adds issues found after conversion to the validation errors of a provider.
+fn with_issues(errors: NormalizationError, issues: Vec<NormalizationError>) -> NormalizationError {
+    if issues.is_empty() {
+        return errors;
+    }
+
+    let mut output = match errors {
+        NormalizationError::None => Vec::new(),
+        NormalizationError::Aggregate(output) => output,
+        err => vec![err],
+    };
+    output.extend(issues);
+    NormalizationError::Aggregate(output)
+}
+
+/// Normalizes every file inside an input file, and moves it to the input archive once all of them
+/// succeed.
+fn process_file(config: &Config, store: &mut Store, file_path: &str, file_name: &str, normalized_path: &str) {
+    let bytes = match std::fs::read(file_path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            println!("error processing file data: {:?}", NormalizationError::Unknown(format!("error reading file {}: {}", file_path, err)));
+            return;
+        }
+    };
+    let inputs = match input::expand(file_name, bytes, &config.archive) {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            println!("error processing file data: {:?}", err);
+            let errors = vec![("errors.json".to_string(), serde_json::json!([err]).to_string())];
+            if let Err(err) = backfill::quarantine(config, file_path, file_name, &errors) {
+                println!("error moving failed file: {}: {:?}", file_name, err);
+            }
+            return;
+        }
+    };
+
+    let mut errors = Vec::new();
+    let mut outputs = Vec::new();
+    for input in inputs {
+        match handle_data(config, &input) {
+            Ok((mut normalized, errors)) => {
+                let mut issues = match config.dedup.deduplicate(store, &mut normalized) {
+                    Ok(issues) => issues.into_iter().map(NormalizationError::Duplicate).collect(),
+                    Err(err) => vec![err],
+                };
+                if let Err(err) = store.assign_versions(&mut normalized) {
+                    issues.push(err);
+                }
+                let result = (normalized, with_issues(errors, issues));
+                let output_name = format!("normalize_{}.n", input.output_name());
+                let output = serde_json::json!(result.0).to_string();
+                let _ = std::fs::write(format!("{}/{}", normalized_path, output_name), &output);
+                outputs.push((output_name, output));
+                let followup: Vec<&NormalizeData> = result.0.iter()
+                    .filter(|data| data.status != RecordStatus::Retracted)
+                    .filter(|data| data.flag.is_some_and(|flag| flag >= config.followup_severity))
+                    .collect();
+                if !followup.is_empty() {
+                    let followup_path = format!("{}/followup_{}.n", config.followup_dir, input.output_name());
+                    let _ = std::fs::write(followup_path, serde_json::json!(followup).to_string());
+                }
+                if let Err(err) = store.insert(&result.0) {
+                    println!("error storing file: {}: {:?}", input.output_name(), err);
+                }
+                if let Err(err) = timeline::record(&config.history_dir, &config.timeline_dir, &config.instruments, &result.0) {
+                    println!("error recording history: {}: {:?}", input.output_name(), err);
+                }
+                if result.1 != NormalizationError::None {
+                    println!("Error processing file: {}\n{}",
+                        input.output_name(),
+                        serde_json::json!(result.1));
+                }
+            },
+            Err(err) => {
+                println!("error processing file data: {}: {:?}", input.output_name(), err);
+                errors.push(serde_json::json!({"input": input.output_name(), "error": err}));
+            }
+        }
+    }
+
+    // A file with a failed member is moved aside rather than left in the input directory, where
+    // its other members would be normalized and stored again on every pass.
+    if errors.is_empty() {
+        if let Err(err) = backfill::archive(config, file_path, file_name, &outputs) {
+            println!("error archiving file: {}: {:?}", file_name, err);
+        }
+    } else {
+        outputs.push(("errors.json".to_string(), serde_json::json!(errors).to_string()));
+        if let Err(err) = backfill::quarantine(config, file_path, file_name, &outputs) {
+            println!("error moving failed file: {}: {:?}", file_name, err);
+        }
+    }
+}
+
+/// Runs `query [--patient ID] [--provider NAME] [--type TYPE] [--dimension NAME] [--from DATE]
+/// [--to DATE] [--format json|csv] [--all-versions]` against the database and prints the
+/// matching scores.
+fn run_query(store: &Store, args: &[String]) -> Result<(), NormalizationError> {
+    let mut query = ScoreQuery::default();
+    let mut csv = false;
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if flag == "--all-versions" {
+            query.all_versions = true;
+            continue;
+        }
+        let value = args.next()
+            .ok_or_else(|| NormalizationError::Parse(format!("missing value of {}", flag)))?
+            .clone();
+        match flag.as_str() {
+            "--patient" => query.patient = Some(value),
+            "--provider" => query.provider = Some(value),
+            "--type" => query.assessment_type = Some(value),
+            "--dimension" => query.dimension = Some(value),
+            "--from" => query.from = Some(value),
+            "--to" => query.to = Some(value),
+            "--format" if value == "json" || value == "csv" => csv = value == "csv",
+            _ => return Err(NormalizationError::Parse(format!("invalid query argument: {} {}", flag, value))),
+        }
+    }
+
+    let rows = store.query(&query)?;
+    if csv {
+        store::write_csv(std::io::stdout(), &rows)
+    } else {
+        println!("{}", serde_json::json!(rows));
+        Ok(())
+    }
+}
+
+/// Runs `explain FILE [--patient ID] [--dimension NAME] [--format text|json]`, which prints how
+/// every score of an input file would be produced. Nothing is written or stored.
+fn run_explain(config: &Config, store: &Store, args: &[String]) -> Result<(), NormalizationError> {
+    let (file_path, args) = args.split_first()
+        .ok_or_else(|| NormalizationError::Parse("missing input file to explain".to_string()))?;
+    let (mut patient, mut dimension, mut json) = (None, None, false);
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(NormalizationError::Parse(format!("missing value of {}", pair[0])));
+        };
+        match flag.as_str() {
+            "--patient" => patient = Some(value.clone()),
+            "--dimension" => dimension = Some(value.clone()),
+            "--format" if value == "text" || value == "json" => json = value == "json",
+            _ => return Err(NormalizationError::Parse(format!("invalid explain argument: {} {}", flag, value))),
+        }
+    }
+
+    let bytes = std::fs::read(file_path)
+        .map_err(|err| NormalizationError::Unknown(format!("error reading file {}: {}", file_path, err)))?;
+    let file_name = std::path::Path::new(file_path).file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(file_path);
+    for input in input::expand(file_name, bytes, &config.archive)? {
+        let explanation = explain::explain(config, store, &input, patient.as_deref(), dimension.as_deref())?;
+        if json {
+            println!("{}", serde_json::json!(explanation));
+        } else {
+            print!("{}", explanation);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `backfill [--from DATE] [--to DATE] [--provider NAME]...`, which normalizes the archived
+/// input files processed in the date range again, writes the outputs under the backfill directory
+/// and prints which of them changed.
+fn run_backfill(config: &Config, args: &[String]) -> Result<(), NormalizationError> {
+    let mut range = BackfillRange::default();
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else {
+            return Err(NormalizationError::Parse(format!("missing value of {}", pair[0])));
+        };
+        let date = || chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map(|_| value.clone())
+            .map_err(|_| NormalizationError::Parse(format!("invalid date of {}: {}", flag, value)));
+        match flag.as_str() {
+            "--from" => range.from = Some(date()?),
+            "--to" => range.to = Some(date()?),
+            "--provider" => range.providers.push(value.clone()),
+            _ => return Err(NormalizationError::Parse(format!("invalid backfill argument: {} {}", flag, value))),
+        }
+    }
+
+    let report = backfill::backfill(config, &range)?;
+    println!("Backfill written to {}: {} changed, {} unchanged, {} new, {} failed",
+        report.output_dir,
+        report.count(OutputStatus::Changed),
+        report.count(OutputStatus::Unchanged),
+        report.count(OutputStatus::New),
+        report.count(OutputStatus::Failed));
+    for output in report.outputs.iter().filter(|output| output.status != OutputStatus::Unchanged) {
+        println!("{} {} {}", store::serde_name(&output.status).unwrap_or_default(), output.entry, output.output);
+        for change in &output.changes {
+            println!("  {}", change);
+        }
+    }
+
+    Ok(())
 }
 
 fn main() {
-    println!("Handling files of type a, b, c");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match Config::load("./config.json") {
+        Ok(config) => config,
+        Err(err) => {
+            println!("error loading config: {:?}", err);
+            return;
+        }
+    };
+    // Backfill runs next to the watcher, and leaves its database alone.
+    if args.first().is_some_and(|command| command == "backfill") {
+        if let Err(err) = run_backfill(&config, &args[1..]) {
+            eprintln!("error running backfill: {:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut store = match Store::open(&config.database) {
+        Ok(store) => store,
+        Err(err) => {
+            println!("error opening database: {:?}", err);
+            return;
+        }
+    };
+    if args.first().is_some_and(|command| command == "query") {
+        if let Err(err) = run_query(&store, &args[1..]) {
+            eprintln!("error running query: {:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.first().is_some_and(|command| command == "explain") {
+        if let Err(err) = run_explain(&config, &store, &args[1..]) {
+            eprintln!("error explaining file: {:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    println!("Handling files of type a, b, c, fhir, hl7, xlsx, xml");
     println!("Press Ctrl-C to quit");
     // TODO: add error checking
     let input_path = "./input";
     let normalized_path = "./normalized";
     let _ = std::fs::create_dir_all(input_path);
     let _ = std::fs::create_dir_all(normalized_path);
+    let _ = std::fs::create_dir_all(&config.input_archive_dir);
+    let _ = std::fs::create_dir_all(&config.followup_dir);
+    let _ = std::fs::create_dir_all(&config.history_dir);
+    let _ = std::fs::create_dir_all(&config.timeline_dir);
 
     loop {
         std::thread::sleep(Duration::from_secs(3));
         if let Ok(entries) = std::fs::read_dir(input_path) {
             println!("Reading input dir: {}", input_path);
-            for entry in entries {
-                if let Ok(entry) = entry {
-                    println!("file found: {:?}", entry);
-                    if let Some(maybe_file_type) = entry.path().extension() {
-                        let file_type = maybe_file_type.to_str();
-                        if let Some(file_type) = file_type {
-                            if let Some(file_path) = entry.path().to_str() {
-                                match handle_data(&file_type, &file_path) {
-                                    Ok(result) => {
-                                        let output_path = format!("{}/normalize_{}.n", normalized_path, entry.file_name().to_str().unwrap_or("name_missing"));
-                                        let _ = std::fs::write(output_path, serde_json::json!(result.0).to_string());
-                                        if result.1 != NormalizationError::None {
-                                            println!("Error processing file: {}\n{}", 
-                                                file_path, 
-                                                serde_json::json!(result.1).to_string());
-                                        }
-
-                                        std::fs::remove_file(file_path);
-                                    },
-                                    Err(err) => {
-                                        println!("error processing file data: {:?}", err);
-                                    }
-
-                                }
-                            }
-                        }
-                    }
+            for entry in entries.flatten() {
+                println!("file found: {:?}", entry);
+                let path = entry.path();
+                if path.extension().is_some() && let Some(file_path) = path.to_str() {
+                    let file_name = entry.file_name().to_str().unwrap_or("name_missing").to_string();
+                    process_file(&config, &mut store, file_path, &file_name, normalized_path);
                 }
             }
 
         }
-        
+
     }
 }
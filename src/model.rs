@@ -3,21 +3,387 @@ use serde::{Serialize, Deserialize, Deserializer, de::Error};
 use std::collections::{BTreeMap, HashSet};
 use chrono::{DateTime, NaiveDate, FixedOffset, Utc};
 
+use crate::dedup::DuplicateIssue;
+use crate::instruments::Completeness;
+use crate::norms::NormIssue;
+use crate::ranges::RangeIssue;
+use crate::severity::Severity;
+
+/// An exact decimal number: `units` shifted right by `precision` decimal places, so 87.50 is
+/// 8750 at precision 2.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decimal {
+    pub units: i64,
+    pub precision: u32,
+}
+
+impl Decimal {
+    /// Parses decimal text such as `-87.50`, keeping the number of decimal places given.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.contains(['e', 'E']) {
+            return text.parse::<f64>().ok().and_then(Self::from_f64);
+        }
+
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        let digits = whole.trim_start_matches(['-', '+']);
+        if (digits.is_empty() && fraction.is_empty()) ||
+            !digits.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let units = format!("{}{}", whole, fraction).parse::<i64>().ok()?;
+        Some(Self {units, precision: fraction.len() as u32})
+    }
+
+    /// Converts a float using its shortest exact representation, e.g. `87.5` at precision 1.
+    pub fn from_f64(value: f64) -> Option<Self> {
+        if !value.is_finite() {
+            return None;
+        }
+        Self::parse(&value.to_string())
+    }
+
+    /// Rounds a float half away from zero to `precision` decimal places.
+    pub fn round_f64(value: f64, precision: u32) -> Option<Self> {
+        let units = (value * 10f64.powi(precision as i32)).round();
+        if !units.is_finite() || units.abs() >= i64::MAX as f64 {
+            return None;
+        }
+        Some(Self {units: units as i64, precision})
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.units as f64 / 10f64.powi(self.precision as i32)
+    }
+
+    /// The same value with `precision` decimal places, if no digits are lost.
+    pub fn with_precision(&self, precision: u32) -> Option<Self> {
+        if precision >= self.precision {
+            let units = self.units.checked_mul(10i64.checked_pow(precision - self.precision)?)?;
+            return Some(Self {units, precision});
+        }
+
+        let divisor = 10i64.checked_pow(self.precision - precision)?;
+        (self.units % divisor == 0).then_some(Self {units: self.units / divisor, precision})
+    }
+
+    pub fn checked_mul(&self, factor: i64) -> Option<Self> {
+        Some(Self {units: self.units.checked_mul(factor)?, precision: self.precision})
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.precision == 0 {
+            return write!(f, "{}", self.units);
+        }
+
+        let digits = format!("{:0width$}", self.units.unsigned_abs(), width = self.precision as usize + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - self.precision as usize);
+        write!(f, "{}{}.{}", if self.units < 0 {"-"} else {""}, whole, fraction)
+    }
+}
+
+/// Why a score has no value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingReason {
+    NotAdministered,
+    Refused,
+    NotApplicable,
+    Unknown,
+}
+
+impl MissingReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MissingReason::NotAdministered => "not_administered",
+            MissingReason::Refused => "refused",
+            MissingReason::NotApplicable => "not_applicable",
+            MissingReason::Unknown => "unknown",
+        }
+    }
+}
+
+/// The result of a score: a number, a categorical or free-text answer, or a missing value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(into = "RawScoreValue", try_from = "RawScoreValue")]
+pub enum ScoreValue {
+    Integer(i64),
+    Decimal(Decimal),
+    Category(String),
+    Text(String),
+    Missing(MissingReason),
+}
+
+impl ScoreValue {
+    /// Parses numeric text as an integer, or a decimal keeping its precision.
+    pub fn parse_number(text: &str) -> Option<Self> {
+        let text = text.trim();
+        match text.parse::<i64>() {
+            Ok(value) => Some(ScoreValue::Integer(value)),
+            Err(_) => Decimal::parse(text).map(ScoreValue::Decimal),
+        }
+    }
+
+    /// Reads a JSON number as an integer, or a decimal when it has a fractional part.
+    pub fn from_json_number(value: &serde_json::Value) -> Option<Self> {
+        match value.as_i64() {
+            Some(value) => Some(ScoreValue::Integer(value)),
+            None => value.as_f64().and_then(Decimal::from_f64).map(ScoreValue::Decimal),
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ScoreValue::Integer(value) => Some(*value as f64),
+            ScoreValue::Decimal(value) => Some(value.to_f64()),
+            ScoreValue::Category(_) | ScoreValue::Text(_) | ScoreValue::Missing(_) => None,
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        matches!(self, ScoreValue::Missing(_))
+    }
+
+    /// Multiplies a numeric value, keeping the precision of decimals. Missing values stay missing.
+    pub fn checked_mul(&self, factor: i64) -> Option<Self> {
+        match self {
+            ScoreValue::Integer(value) => value.checked_mul(factor).map(ScoreValue::Integer),
+            ScoreValue::Decimal(value) => value.checked_mul(factor).map(ScoreValue::Decimal),
+            ScoreValue::Missing(reason) => Some(ScoreValue::Missing(*reason)),
+            ScoreValue::Category(_) | ScoreValue::Text(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ScoreValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScoreValue::Integer(value) => write!(f, "{}", value),
+            ScoreValue::Decimal(value) => write!(f, "{}", value),
+            ScoreValue::Category(value) | ScoreValue::Text(value) => write!(f, "{:?}", value),
+            ScoreValue::Missing(reason) => write!(f, "missing ({})", reason.name()),
+        }
+    }
+}
+
+/// Output form of a `ScoreValue`. Integers are written as a bare `value`, decimals add their
+/// `precision`, categorical and text results add a `valueType`, and missing values are a null
+/// `value` with the `missing` reason.
 #[derive(Clone, Serialize, Deserialize)]
+struct RawScoreValue {
+    value: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    missing: Option<MissingReason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    precision: Option<u32>,
+    #[serde(default, rename = "valueType", skip_serializing_if = "Option::is_none")]
+    value_type: Option<String>,
+}
+
+impl From<ScoreValue> for RawScoreValue {
+    fn from(value: ScoreValue) -> Self {
+        let (value, precision, value_type) = match value {
+            ScoreValue::Integer(value) => (value.into(), None, None),
+            ScoreValue::Decimal(value) => (value.to_f64().into(), Some(value.precision), None),
+            ScoreValue::Category(value) => (value.into(), None, Some("categorical".to_string())),
+            ScoreValue::Text(value) => (value.into(), None, Some("text".to_string())),
+            ScoreValue::Missing(reason) => {
+                return Self {value: serde_json::Value::Null, missing: Some(reason), precision: None, value_type: None};
+            },
+        };
+        Self {value, missing: None, precision, value_type}
+    }
+}
+
+impl TryFrom<RawScoreValue> for ScoreValue {
+    type Error = String;
+
+    fn try_from(raw: RawScoreValue) -> Result<Self, Self::Error> {
+        if let Some(reason) = raw.missing {
+            return Ok(ScoreValue::Missing(reason));
+        }
+
+        match (raw.value_type.as_deref(), raw.precision, &raw.value) {
+            (Some("categorical"), _, serde_json::Value::String(value)) => Ok(ScoreValue::Category(value.clone())),
+            (Some("text"), _, serde_json::Value::String(value)) => Ok(ScoreValue::Text(value.clone())),
+            (None, Some(precision), value) => value.as_f64()
+                .and_then(Decimal::from_f64)
+                .and_then(|value| value.with_precision(precision))
+                .map(ScoreValue::Decimal)
+                .ok_or_else(|| format!("invalid decimal score value: {}", value)),
+            (None, None, value) => value.as_i64()
+                .map(ScoreValue::Integer)
+                .ok_or_else(|| format!("invalid integer score value: {}", value)),
+            (value_type, _, value) => Err(format!("invalid {:?} score value: {}", value_type, value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct NormalizeScore {
     pub dimension: String,
-    pub value: i64,
+    #[serde(flatten)]
+    pub value: ScoreValue,
     pub scale: String,
+    /// Computed by the scoring engine or from norms rather than read from the source.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub derived: bool,
+    /// Severity band of the score, when its instrument has cut-offs for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interpretation: Option<Severity>,
+    /// Where the score was read from and how, so it can be traced back to its input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<ScoreLineage>,
 }
 
+/// Provenance of one score. Providers locate the score in their input, and the input file and its
+/// checksum are added once the score is normalized. Derived scores name the scores they are
+/// computed from in `field`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScoreLineage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_file: Option<String>,
+    /// SHA-256 of the input file, in hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Index of the provider record, as reported in validation errors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<usize>,
+    /// Line of the record in the input, or row of the sheet, from 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// Path of the source field, e.g. `assessment.scores.anxiety`, `score_memory` or `metric_value`.
+    pub field: String,
+    /// Source value as written in the input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    /// How the value was obtained from the raw value, e.g. `x10` or `missing token`.
+    pub transform: String,
+}
 
-#[derive(Clone, Serialize, Deserialize)]
+impl ScoreLineage {
+    pub fn new(record: usize, line: Option<usize>, field: impl Into<String>, raw: impl Into<String>, transform: impl Into<String>) -> Self {
+        Self {
+            record: Some(record),
+            line,
+            field: field.into(),
+            raw: Some(raw.into()),
+            transform: transform.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn derived(field: impl Into<String>, transform: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            transform: transform.into(),
+            ..Self::default()
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sex {
+    Female,
+    Male,
+    Other,
+}
+
+impl Sex {
+    /// Reads the usual source codes, e.g. `F`, `female` or HL7 `O`; anything else is unknown.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "f" | "female" => Some(Sex::Female),
+            "m" | "male" => Some(Sex::Male),
+            "o" | "other" => Some(Sex::Other),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sex::Female => "female",
+            Sex::Male => "male",
+            Sex::Other => "other",
+        }
+    }
+}
+
+/// Whether an assessment is as first sent, a correction of an earlier version, or withdrawn, e.g.
+/// when entered for the wrong patient.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordStatus {
+    #[default]
+    Original,
+    Amended,
+    Retracted,
+}
+
+impl RecordStatus {
+    /// Reads a status marker, also accepting the FHIR and HL7 names of corrections and errors.
+    /// Blank markers are original records.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "original" | "final" => Some(RecordStatus::Original),
+            "amended" | "corrected" | "correction" => Some(RecordStatus::Amended),
+            "retracted" | "entered-in-error" | "deleted" | "wrong" => Some(RecordStatus::Retracted),
+            _ => None,
+        }
+    }
+
+    pub fn is_original(&self) -> bool {
+        *self == RecordStatus::Original
+    }
+}
+
+/// Patient details used for norming, as far as the source provides them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Demographics {
+    pub date_of_birth: Option<NaiveDate>,
+    pub sex: Option<Sex>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct NormalizeData {
     pub patientId: String,
     pub assessmentDate: String,
+    /// Whether the source gave no assessment date, so that `assessmentDate` is the processing time.
+    #[serde(default, rename = "assessmentDateUnknown", skip_serializing_if = "std::ops::Not::not")]
+    pub date_unknown: bool,
     pub assessmentType: String,
     pub scores: Vec<NormalizeScore>,
     pub metadata: BTreeMap<String, String>,
+    /// How the assessment compares with its instrument, when the type is in the catalogue.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completeness: Option<Completeness>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub demographics: Option<Demographics>,
+    /// Most severe interpretation of the scores.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flag: Option<Severity>,
+    /// Identifier of the assessment in the source, shared by all of its versions.
+    #[serde(default, rename = "recordId", skip_serializing_if = "Option::is_none")]
+    pub record_id: Option<String>,
+    #[serde(default, skip_serializing_if = "RecordStatus::is_original")]
+    pub status: RecordStatus,
+    /// Version of the assessment, counting from 1.
+    #[serde(default = "first_version")]
+    pub version: u32,
+    /// Fingerprint of the version this one replaces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<String>,
+}
+
+fn first_version() -> u32 {
+    1
 }
 
 impl NormalizeData {
@@ -30,10 +396,22 @@ impl NormalizeData {
             patientId,
             assessmentType,
             assessmentDate: date.to_rfc3339(),
+            date_unknown: false,
             scores: Vec::new(),
             metadata: metadata,
+            completeness: None,
+            demographics: None,
+            flag: None,
+            record_id: None,
+            status: RecordStatus::Original,
+            version: 1,
+            supersedes: None,
         }
+    }
 
+    /// An assessment whose source gives no date, dated at processing time.
+    pub fn undated(patient_id: String, assessment_type: String, metadata: BTreeMap<String, String>) -> Self {
+        Self {date_unknown: true, ..Self::new(patient_id, assessment_type, Utc::now(), metadata)}
     }
 }
 
@@ -42,6 +420,11 @@ pub enum NormalizationError{
     None,
     Parse(String),
     Validate(String, usize),
+    ParseRow(String, usize, String),
+    Decode(String, Vec<usize>),
+    Range(RangeIssue),
+    Norm(NormIssue),
+    Duplicate(DuplicateIssue),
     Aggregate(Vec<NormalizationError>),
     Unknown(String),
 }
@@ -49,6 +432,40 @@ pub enum NormalizationError{
 pub trait Provider {
     fn get_metadata(&self) -> BTreeMap<String, String>;
     fn parse(&mut self, data: &str) -> Result<(), NormalizationError>;
+    /// Parses a raw input file. Text formats decode it to UTF-8 first, binary formats override this.
+    fn parse_bytes(&mut self, data: &[u8], encoding: Option<&str>) -> Result<(), NormalizationError> {
+        let data = crate::encoding::decode(data, encoding)?;
+        self.parse(&data)
+    }
     fn validate(&mut self) -> NormalizationError;
     fn convert(&self) -> Vec<NormalizeData>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_value_round_trip_test() {
+        let scores = vec![
+            NormalizeScore {dimension: "memory".into(), value: ScoreValue::Integer(85), scale: "0-100".into(), derived: false, interpretation: None, lineage: None},
+            NormalizeScore {dimension: "t".into(), value: ScoreValue::parse_number("-1.50").unwrap(), scale: "z".into(), derived: false, interpretation: None, lineage: None},
+            NormalizeScore {dimension: "mood".into(), value: ScoreValue::Category("low".into()), scale: "".into(), derived: false, interpretation: None, lineage: None},
+            NormalizeScore {dimension: "note".into(), value: ScoreValue::Text("calm".into()), scale: "".into(), derived: false, interpretation: None, lineage: None},
+            NormalizeScore {dimension: "skip".into(), value: ScoreValue::Missing(MissingReason::Refused), scale: "".into(), derived: false, interpretation: None, lineage: None},
+        ];
+
+        let json = serde_json::json!(scores).to_string();
+        assert_eq!(json, concat!(
+            r#"[{"dimension":"memory","scale":"0-100","value":85},"#,
+            r#"{"dimension":"t","precision":2,"scale":"z","value":-1.5},"#,
+            r#"{"dimension":"mood","scale":"","value":"low","valueType":"categorical"},"#,
+            r#"{"dimension":"note","scale":"","value":"calm","valueType":"text"},"#,
+            r#"{"dimension":"skip","missing":"refused","scale":"","value":null}]"#));
+        let parsed: Vec<NormalizeScore> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, scores);
+        assert_eq!(parsed[1].value, ScoreValue::Decimal(Decimal {units: -150, precision: 2}));
+        assert_eq!(Decimal {units: -150, precision: 2}.to_string(), "-1.50");
+        assert_eq!(Decimal::parse("0.05").map(|value| value.to_string()), Some("0.05".into()));
+    }
+}
@@ -0,0 +1,221 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeSet;
+use chrono::DateTime;
+
+use crate::config::load_json_dir;
+use crate::model::{Decimal, NormalizationError, NormalizeData, NormalizeScore, ScoreLineage, ScoreValue, Sex};
+
+/// The standard score a norm table produces.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NormScale {
+    /// Mean 50, standard deviation 10.
+    TScore,
+    Percentile,
+}
+
+impl NormScale {
+    fn suffix(&self) -> &'static str {
+        match self {
+            NormScale::TScore => "t",
+            NormScale::Percentile => "percentile",
+        }
+    }
+}
+
+/// Normative mean and standard deviation of one age band, in whole years from `min_age` up to
+/// but not including `max_age`. Bands without `sex` apply to everyone.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct NormBand {
+    #[serde(default)]
+    pub sex: Option<Sex>,
+    pub min_age: u32,
+    pub max_age: u32,
+    pub mean: f64,
+    pub sd: f64,
+}
+
+/// Norms of one dimension of an instrument.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct NormTable {
+    pub assessment_type: String,
+    pub dimension: String,
+    pub version: String,
+    pub scale: NormScale,
+    #[serde(default = "default_precision")]
+    pub precision: u32,
+    pub bands: Vec<NormBand>,
+}
+
+fn default_precision() -> u32 {
+    1
+}
+
+/// Why a score could not be normed.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct NormIssue {
+    /// Index of the normalized assessment.
+    pub record: usize,
+    pub assessment_type: String,
+    pub dimension: String,
+    pub reason: String,
+}
+
+/// Standard normal cumulative distribution, using the Abramowitz and Stegun approximation of erf.
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let polynomial = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - polynomial * (-x * x).exp();
+    if z >= 0.0 {0.5 * (1.0 + erf)} else {0.5 * (1.0 - erf)}
+}
+
+impl NormTable {
+    /// The band for an age and sex, preferring a band for that sex over one for everyone.
+    fn band(&self, age: u32, sex: Option<Sex>) -> Result<&NormBand, String> {
+        let in_age = |band: &&NormBand| (band.min_age..band.max_age).contains(&age);
+        if let Some(band) = self.bands.iter().filter(in_age).find(|band| band.sex.is_some() && band.sex == sex) {
+            return Ok(band);
+        }
+        if let Some(band) = self.bands.iter().filter(in_age).find(|band| band.sex.is_none()) {
+            return Ok(band);
+        }
+
+        match sex {
+            None if self.bands.iter().any(|band| in_age(&band)) => Err(format!("sex is missing and the norms for age {} are by sex", age)),
+            None => Err(format!("no norms for age {}", age)),
+            Some(sex) => Err(format!("no norms for age {} and sex {}", age, sex.name())),
+        }
+    }
+
+    fn standard_score(&self, band: &NormBand, value: f64) -> Option<ScoreValue> {
+        let z = (value - band.mean) / band.sd;
+        let score = match self.scale {
+            NormScale::TScore => 50.0 + 10.0 * z,
+            NormScale::Percentile => 100.0 * normal_cdf(z),
+        };
+        Decimal::round_f64(score, self.precision).map(ScoreValue::Decimal)
+    }
+}
+
+/// Normative tables, maintained as one JSON file per table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NormCatalog(pub Vec<NormTable>);
+
+impl NormCatalog {
+    pub fn load_dir(path: &str) -> Result<Self, NormalizationError> {
+        let mut catalog = Self::default();
+        for (name, table) in load_json_dir::<NormTable>(path)? {
+            if table.bands.iter().any(|band| band.sd <= 0.0) {
+                return Err(NormalizationError::Parse(format!("{}: standard deviations must be positive", name)));
+            }
+            catalog.0.push(table);
+        }
+
+        Ok(catalog)
+    }
+
+    /// Adds a standard score after every score that has norms, named `<dimension>_t` or
+    /// `<dimension>_percentile`. Scores that cannot be normed, e.g. for lack of a date of birth,
+    /// are reported rather than normed with a guessed age or sex.
+    pub fn apply(&self, record: usize, data: &mut NormalizeData) -> Vec<NormIssue> {
+        let mut issues = Vec::new();
+        let mut versions = BTreeSet::new();
+        let demographics = data.demographics.clone().unwrap_or_default();
+        let assessment_date = DateTime::parse_from_rfc3339(&data.assessmentDate).ok().map(|date| date.date_naive());
+        let age = match (demographics.date_of_birth, assessment_date) {
+            (None, _) => Err("date of birth is missing".to_string()),
+            _ if data.date_unknown => Err("assessment date unknown".to_string()),
+            (_, None) => Err("assessment date is invalid".to_string()),
+            (Some(dob), Some(date)) => date.years_since(dob).ok_or_else(|| "date of birth is after the assessment".to_string()),
+        };
+
+        let mut scores = Vec::new();
+        for score in data.scores.drain(..) {
+            let normed = self.0.iter()
+                .filter(|table| table.assessment_type == data.assessmentType && table.dimension == score.dimension)
+                .map(|table| {
+                    let age = age.as_ref().map_err(|reason| reason.clone())?;
+                    let band = table.band(*age, demographics.sex)?;
+                    let value = score.value.as_f64().ok_or_else(|| "score is not numeric".to_string())?;
+                    let value = table.standard_score(band, value).ok_or_else(|| "standard score is out of range".to_string())?;
+                    versions.insert(table.version.clone());
+                    let transform = format!("{} with mean {} and sd {} for age {}{} (norms {})", table.scale.suffix(),
+                        band.mean, band.sd, age, band.sex.map(|sex| format!(" and sex {}", sex.name())).unwrap_or_default(), table.version);
+                    Ok(NormalizeScore {
+                        dimension: format!("{}_{}", score.dimension, table.scale.suffix()),
+                        value,
+                        scale: table.scale.suffix().to_string(),
+                        derived: true,
+                        interpretation: None,
+                        lineage: Some(ScoreLineage::derived(score.dimension.clone(), transform)),
+                    })
+                })
+                .collect::<Vec<Result<_, String>>>();
+
+            let dimension = score.dimension.clone();
+            let missing = score.value.is_missing();
+            scores.push(score);
+            for result in normed {
+                match result {
+                    Ok(normed) => scores.push(normed),
+                    // Missing values have nothing to norm and are not an issue.
+                    Err(_) if missing => {},
+                    Err(reason) => issues.push(NormIssue {
+                        record,
+                        assessment_type: data.assessmentType.clone(),
+                        dimension: dimension.clone(),
+                        reason,
+                    }),
+                }
+            }
+        }
+        data.scores = scores;
+
+        if !versions.is_empty() {
+            data.metadata.insert("normsVersion".to_string(), versions.into_iter().collect::<Vec<_>>().join(","));
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use std::collections::BTreeMap;
+    use crate::model::Demographics;
+
+    #[test]
+    fn norms_test() {
+        let catalog = NormCatalog::load_dir("norms").unwrap();
+        let mut data = NormalizeData::new(
+            "P1".into(), "cognitive".into(), Utc.with_ymd_and_hms(2024, 10, 15, 0, 0, 0).unwrap(), BTreeMap::new());
+        data.scores.push(NormalizeScore {dimension: "memory".into(), value: ScoreValue::Integer(85), scale: "0-100".into(), derived: false, interpretation: None, lineage: None});
+        data.scores.push(NormalizeScore {dimension: "processing".into(), value: ScoreValue::Integer(72), scale: "0-100".into(), derived: false, interpretation: None, lineage: None});
+
+        let issues = catalog.apply(0, &mut data.clone());
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].reason, "date of birth is missing");
+
+        let mut undated = NormalizeData::undated("P1".into(), "cognitive".into(), BTreeMap::new());
+        undated.scores = data.scores.clone();
+        undated.demographics = Some(Demographics {date_of_birth: NaiveDate::from_ymd_opt(2016, 2, 21), sex: None});
+        let issues = catalog.apply(0, &mut undated);
+        assert_eq!(issues.iter().map(|issue| issue.reason.as_str()).collect::<Vec<_>>(), vec!["assessment date unknown"; 2]);
+
+        data.demographics = Some(Demographics {date_of_birth: NaiveDate::from_ymd_opt(2016, 2, 21), sex: None});
+        let issues = catalog.apply(0, &mut data);
+        assert_eq!(issues, vec![NormIssue {
+            record: 0,
+            assessment_type: "cognitive".into(),
+            dimension: "processing".into(),
+            reason: "sex is missing and the norms for age 8 are by sex".into(),
+        }]);
+        assert_eq!(data.scores.len(), 3);
+        assert_eq!(data.scores[1].dimension, "memory_t");
+        assert_eq!(data.scores[1].value, ScoreValue::Decimal(Decimal {units: 600, precision: 1}));
+        assert_eq!(data.metadata["normsVersion"], "example-2024");
+        assert!((normal_cdf(1.0) - 0.841345).abs() < 1e-6);
+    }
+}
@@ -4,7 +4,9 @@ use std::collections::{BTreeMap, HashSet};
 use chrono::{DateTime, NaiveDate, FixedOffset, Utc};
 use chrono::ParseError;
 
-use crate::model::{NormalizationError, NormalizeData, NormalizeScore, Provider};
+use crate::config::{MissingTokens, SourceConfig};
+use crate::ranges::{Range, RangeCatalog, RangeStage, Scaling};
+use crate::model::{Demographics, Sex, NormalizationError, NormalizeData, NormalizeScore, Provider, RecordStatus, ScoreLineage, ScoreValue};
 
 fn parse_dob<'de, D>(deserializer: D) -> Result<Option<NaiveDate>, D::Error>
 where
@@ -18,44 +20,124 @@ where
     return Ok(None);
 }
 
+/// Scale of source scores without a reference range.
+pub const SOURCE_RANGE: Range = Range {min: 0.0, max: 10.0};
+
+/// Source scores are mapped from their source range onto their target range, by default 0-10
+/// onto 0-100, keeping any decimal places. Missing tokens become missing values.
+fn scaled_score(missing: &MissingTokens, scaling: &Scaling, value: &serde_json::Value) -> Option<ScoreValue> {
+    if let Some(reason) = missing.reason_json(value) {
+        return Some(ScoreValue::Missing(reason));
+    }
+
+    scaling.apply(&ScoreValue::from_json_number(value)?)
+}
+
+/// The transform `scaled_score` applies to a value.
+fn score_transform(missing: &MissingTokens, scaling: &Scaling, value: &serde_json::Value) -> String {
+    if missing.reason_json(value).is_some() {"missing token".to_string()} else {scaling.transform()}
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Patient {
     pub id: String,
     pub name: String,
     #[serde(deserialize_with = "parse_dob")]
     pub dob: Option<NaiveDate>,
+    #[serde(default)]
+    pub sex: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Assessment {
     #[serde(rename = "type")] 
     pub type_: String,
-    pub scores: BTreeMap<String, i64>,
+    pub scores: BTreeMap<String, serde_json::Value>,
     pub notes: String,
-
+    /// Identifier of the assessment, shared by its amended versions.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// `amended` or `retracted`, absent for original assessments.
+    #[serde(default)]
+    pub status: Option<String>,
 }
 
+/// Assessment type and id of an assessment, or its index in the file when it has no id.
+type AssessmentKey = (String, Result<String, usize>);
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Data {
     pub patient: Patient,
     pub assessment: Assessment,
+    /// Path of the assessment in the file, e.g. `[0].assessment` or `assessments[1]`.
+    #[serde(skip)]
+    pub path: String,
+    /// Line of newline-delimited records.
+    #[serde(skip)]
+    pub line: Option<usize>,
+    /// Schema version of the record the assessment came from, `1.0` or `2.0`.
+    #[serde(skip)]
+    pub schema_version: String,
+}
+
+/// Whether the first line of the file holds a complete JSON object on its own.
+fn is_ndjson(data: &str) -> bool {
+    data.lines()
+        .find(|line| !line.trim().is_empty())
+        .is_some_and(|line| serde_json::from_str::<serde_json::Value>(line).is_ok_and(|value| value.is_object()))
+}
+
+/// Version 2 shape, with every assessment of a patient in one record.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DataV2 {
+    pub patient: Patient,
+    pub assessments: Vec<Assessment>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ProviderHandler {
     pub data: Vec<Data>,
     pub error_index: HashSet<usize>,
+    pub parse_errors: Vec<NormalizationError>,
+    /// How the records were laid out in the file: `array`, `object` or `ndjson`.
+    pub layout: String,
+    pub missing: MissingTokens,
+    pub ranges: RangeCatalog,
 }
 
 impl ProviderHandler {
-    pub fn new() -> Self {
+    pub fn with_source(source: SourceConfig) -> Self {
         Self {
             data: Vec::new(),
             error_index: HashSet::new(),
+            parse_errors: Vec::new(),
+            layout: "array".into(),
+            missing: source.missing,
+            ranges: source.ranges,
         }
     }
 
     pub fn name() -> String {"a".into()}
+
+    /// Adds one record in either schema version, one `Data` per assessment. `prefix` locates the
+    /// record in an array.
+    fn push_record(&mut self, record: serde_json::Value, prefix: &str, line: Option<usize>) -> Result<(), serde_json::Error> {
+        if record.get("assessments").is_some() {
+            let record: DataV2 = serde_json::from_value(record)?;
+            for (index, assessment) in record.assessments.into_iter().enumerate() {
+                let path = format!("{}assessments[{}]", prefix, index);
+                self.data.push(Data { patient: record.patient.clone(), assessment, path, line, schema_version: "2.0".into() });
+            }
+        } else {
+            let mut data: Data = serde_json::from_value(record)?;
+            data.path = format!("{}assessment", prefix);
+            data.line = line;
+            data.schema_version = "1.0".into();
+            self.data.push(data);
+        }
+
+        Ok(())
+    }
 }
 
 impl Provider for ProviderHandler {      
@@ -64,22 +146,46 @@ impl Provider for ProviderHandler {
             ("sourceProvider".to_string(), "provider_a".to_string()),
             ("sourceFormat".to_string(), "nested_json".to_string()),
             ("ingestedAt".to_string(), Utc::now().to_rfc3339(),),
-            ("version".to_string(), "1.0".to_string()),
+            ("schemaLayout".to_string(), self.layout.clone()),
         ])
     }  
 
     fn parse(&mut self, data: &str) -> Result<(), NormalizationError>  {
-        match serde_json::from_str::<Vec<Data>>(data) {
-            Ok(result) => {
-                self.data = result;
-                Ok(())
+        // A single JSON value is an array of records or one record; otherwise the file is
+        // newline-delimited JSON, one record per line.
+        match serde_json::from_str::<serde_json::Value>(data) {
+            Ok(serde_json::Value::Array(records)) => {
+                self.layout = "array".into();
+                for (index, record) in records.into_iter().enumerate() {
+                    self.push_record(record, &format!("[{}].", index), None)
+                        .map_err(|err| NormalizationError::Parse(err.to_string()))?;
+                }
+            },
+            Ok(record) => {
+                self.layout = "object".into();
+                self.push_record(record, "", None).map_err(|err| NormalizationError::Parse(err.to_string()))?;
+            },
+            Err(err) if !is_ndjson(data) => return Err(NormalizationError::Parse(err.to_string())),
+            Err(_) => {
+                self.layout = "ndjson".into();
+                for (index, line) in data.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let result = serde_json::from_str::<serde_json::Value>(line)
+                        .and_then(|record| self.push_record(record, "", Some(index + 1)));
+                    if let Err(err) = result {
+                        self.parse_errors.push(NormalizationError::ParseRow(err.to_string(), index + 1, line.into()));
+                    }
+                }
             },
-            Err(err) => Err(NormalizationError::Parse(err.to_string())),
         }
+
+        Ok(())
     }
 
     fn validate(&mut self) -> NormalizationError {
-        let mut output = Vec::new();
+        let mut output = self.parse_errors.clone();
         for (index, data) in self.data.iter().enumerate() {
             if data.patient.id.len() == 0 &&
                 data.patient.name.len() == 0 &&
@@ -90,6 +196,27 @@ impl Provider for ProviderHandler {
                 output.push(NormalizationError::Validate("Data is invalid".into(), index));
                 self.error_index.insert(index);
             }
+            if let Some(status) = &data.assessment.status && RecordStatus::parse(status).is_none() {
+                output.push(NormalizationError::Validate(format!("Assessment status is invalid: {}", status), index));
+                self.error_index.insert(index);
+            }
+
+            for (dimension, value) in data.assessment.scores.iter() {
+                let scaling = self.ranges.scaling(&data.assessment.type_, dimension, SOURCE_RANGE);
+                if scaled_score(&self.missing, &scaling, value).is_none() {
+                    output.push(NormalizationError::Validate(format!("Score {} is not a number: {}", dimension, value), index));
+                    self.error_index.insert(index);
+                    continue;
+                }
+
+                let raw = ScoreValue::from_json_number(value).filter(|_| self.missing.reason_json(value).is_none());
+                let issue = raw.and_then(|raw|
+                    self.ranges.check(RangeStage::Source, index, &data.assessment.type_, dimension, &raw, Some(SOURCE_RANGE)));
+                if let Some(issue) = issue {
+                    output.push(NormalizationError::Range(issue));
+                    self.error_index.insert(index);
+                }
+            }
         }
         
         if output.len() > 0 {NormalizationError::Aggregate(output)} else {NormalizationError::None}
@@ -97,7 +224,7 @@ impl Provider for ProviderHandler {
 
     fn convert(&self) -> Vec<NormalizeData> {
         let metadata = self.get_metadata();
-        let mut patients: BTreeMap<String, BTreeMap<String, NormalizeData>> = BTreeMap::new();
+        let mut patients: BTreeMap<String, BTreeMap<AssessmentKey, NormalizeData>> = BTreeMap::new();
         for (index, data) in self.data.iter().enumerate() {
             if self.error_index.contains(&index) {
                 continue;
@@ -109,17 +236,32 @@ impl Provider for ProviderHandler {
             }
             if let Some(assessments) = patients.get_mut(&id) {
                 let assessment_type = data.assessment.type_.clone();
-                if !assessments.contains_key(&assessment_type) {
-                    assessments.insert(assessment_type.clone(), 
-                        NormalizeData::new(id, assessment_type.clone(), Utc::now(), metadata.clone()));
+                // Assessments without an id are each their own assessment, keyed by their index.
+                let key = (assessment_type.clone(), data.assessment.id.clone().ok_or(index));
+                if !assessments.contains_key(&key) {
+                    let mut normalized_data = NormalizeData::undated(id, assessment_type, metadata.clone());
+                    normalized_data.demographics = Some(Demographics {
+                        date_of_birth: data.patient.dob,
+                        sex: data.patient.sex.as_deref().and_then(Sex::parse),
+                    });
+                    normalized_data.record_id = data.assessment.id.clone();
+                    normalized_data.metadata.insert("version".to_string(), data.schema_version.clone());
+                    assessments.insert(key.clone(), normalized_data);
                 }
 
-                if let Some(normalized_data) = assessments.get_mut(&assessment_type) {
+                if let Some(normalized_data) = assessments.get_mut(&key) {
+                    let status = data.assessment.status.as_deref().and_then(RecordStatus::parse).unwrap_or_default();
+                    normalized_data.status = normalized_data.status.max(status);
                     for (dimension, value) in data.assessment.scores.iter() {
+                        let scaling = self.ranges.scaling(&normalized_data.assessmentType, dimension, SOURCE_RANGE);
                         normalized_data.scores.push(NormalizeScore {
                             dimension: dimension.to_string(),
-                            value: value * 10,
-                            scale: "0-100".into(),
+                            value: scaled_score(&self.missing, &scaling, value).unwrap(),
+                            scale: scaling.target.scale(),
+                            derived: false,
+                            interpretation: None,
+                            lineage: Some(ScoreLineage::new(index, data.line, format!("{}.scores.{}", data.path, dimension),
+                                value.to_string(), score_transform(&self.missing, &scaling, value))),
                         });
                     }
                 }
@@ -153,7 +295,7 @@ mod tests {
             }
         }]"#;
 
-        let mut handler = ProviderHandler::new();
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
         let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
         assert_eq!(provider.parse(json_str).is_ok(), true);
         assert_eq!(provider.validate(), NormalizationError::None);
@@ -161,4 +303,50 @@ mod tests {
         assert_eq!(converted.len(), 1);
         assert_eq!(converted[0].scores.len(), 3);
     }
+
+    #[test]
+    fn provider_a_variants_test() {
+        let single = r#"{
+            "patient": {"id": "P123a", "name": "test", "dob": "20260221"},
+            "assessment": {"type": "behavioral_screening", "scores": {"anxiety": 7, "social": "NA"}, "notes": ""}
+        }"#;
+        let ndjson = r#"{"patient": {"id": "P123a", "name": "test", "dob": "20260221"}, "assessment": {"type": "behavioral_screening", "scores": {"anxiety": 7}, "notes": ""}}
+{"patient": {"id": "P124a", "name": "test", "dob": "20260221"}, "assessments": [{"type": "behavioral_screening", "scores": {"anxiety": 5}, "notes": ""}, {"type": "cognitive", "scores": {"memory": 8.25}, "notes": ""}]}
+{"patient": {"id": "P125a"}"#;
+
+        let missing = MissingTokens(BTreeMap::from([("NA".to_string(), crate::model::MissingReason::NotApplicable)]));
+        let mut handler = ProviderHandler::with_source(SourceConfig {missing, ..SourceConfig::default()});
+        assert!(handler.parse(single).is_ok());
+        assert_eq!(handler.validate(), NormalizationError::None);
+        let converted = handler.convert();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].scores[1].value, ScoreValue::Missing(crate::model::MissingReason::NotApplicable));
+        assert_eq!(converted[0].metadata["version"], "1.0");
+        assert_eq!(converted[0].metadata["schemaLayout"], "object");
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        assert!(handler.parse(ndjson).is_ok());
+        assert!(matches!(handler.validate(), NormalizationError::Aggregate(errors)
+            if matches!(&errors[..], [NormalizationError::ParseRow(_, 3, _)])));
+        let converted = handler.convert();
+        assert_eq!(converted.len(), 3);
+        assert_eq!(converted[0].metadata["version"], "1.0");
+        assert_eq!(converted[1].metadata["version"], "2.0");
+        assert_eq!(converted[0].metadata["schemaLayout"], "ndjson");
+        assert_eq!(converted[2].scores[0].value, ScoreValue::Decimal(crate::model::Decimal {units: 8250, precision: 2}));
+        assert_eq!(converted[2].scores[0].lineage, Some(ScoreLineage::new(2, Some(2), "assessments[1].scores.memory", "8.25", "x10")));
+
+        let repeated = r#"{"patient": {"id": "P126a", "name": "test", "dob": "20260221"}, "assessments": [
+            {"type": "behavioral_screening", "scores": {"anxiety": 7}, "notes": ""},
+            {"type": "behavioral_screening", "scores": {"anxiety": 5}, "notes": ""}
+        ]}"#;
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        assert!(handler.parse(repeated).is_ok());
+        assert_eq!(handler.validate(), NormalizationError::None);
+        let converted = handler.convert();
+        let scores: Vec<Vec<ScoreValue>> = converted.iter()
+            .map(|data| data.scores.iter().map(|score| score.value.clone()).collect())
+            .collect();
+        assert_eq!(scores, vec![vec![ScoreValue::Integer(70)], vec![ScoreValue::Integer(50)]]);
+    }
 }
@@ -1,9 +1,12 @@
 
 use serde::{Serialize, Deserialize, Deserializer, de::Error};
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use chrono::{DateTime, NaiveDate, FixedOffset, Utc};
+use regex::Regex;
 
-use crate::model::{NormalizationError, NormalizeData, NormalizeScore, Provider};
+use crate::config::{MissingTokens, ScoreKeys, SourceConfig};
+use crate::ranges::{RangeCatalog, RangeStage};
+use crate::model::{NormalizationError, NormalizeData, NormalizeScore, Provider, RecordStatus, ScoreLineage, ScoreValue};
 
 type ValidationFunc = fn(&BTreeMap<String, serde_json::Value>) -> bool;
 const ID: (&str, ValidationFunc) = 
@@ -15,34 +18,142 @@ const TYPE: (&str, ValidationFunc) =
 const NOTES: (&str, ValidationFunc) = 
     ("notes", |data| data.contains_key(NOTES.0) && data[NOTES.0].is_string());
 
+/// Identifier of the assessment, shared by its amended versions.
+const RECORD_ID: (&str, ValidationFunc) =
+    ("record_id", |data| data.get(RECORD_ID.0).is_none_or(|value| value.is_string()));
+/// `amended` or `retracted`, absent for original assessments.
+const RECORD_STATUS: (&str, ValidationFunc) =
+    ("record_status", |data| data.get(RECORD_STATUS.0).is_none_or(|value| value.as_str().and_then(RecordStatus::parse).is_some()));
+
+/// Record fields that are not scores.
+const FIELDS: [&str; 6] = [ID.0, NAME.0, TYPE.0, NOTES.0, RECORD_ID.0, RECORD_STATUS.0];
+
+/// A score in a record, with `key` locating it, e.g. `score_memory` or `scores.memory`.
+struct RecordScore<'a> {
+    key: String,
+    dimension: String,
+    value: &'a serde_json::Value,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ProviderHandler {
     pub data: Vec<BTreeMap<String, serde_json::Value>>,
     pub error_index: HashSet<usize>,
+    pub score_keys: ScoreKeys,
+    pub missing: MissingTokens,
+    pub ranges: RangeCatalog,
+    #[serde(skip)]
+    patterns: Vec<Regex>,
 }
 
 impl ProviderHandler {
-    pub fn new() -> Self {
+    pub fn with_source(source: SourceConfig) -> Self {
         Self {
             data: Vec::new(),
             error_index: HashSet::new(),
+            score_keys: source.score_keys,
+            missing: source.missing,
+            ranges: source.ranges,
+            patterns: Vec::new(),
         }
     }
 
     pub fn name() -> String {"b".into()}
+
+    /// The dimension of a top-level score key, or None if the key is not a score.
+    fn score_dimension(&self, key: &str) -> Option<String> {
+        for prefix in &self.score_keys.prefixes {
+            if let Some(dimension) = key.strip_prefix(prefix.as_str()) && !dimension.is_empty() {
+                return Some(dimension.to_string());
+            }
+        }
+        for suffix in &self.score_keys.suffixes {
+            if let Some(dimension) = key.strip_suffix(suffix.as_str()) && !dimension.is_empty() {
+                return Some(dimension.to_string());
+            }
+        }
+        for pattern in &self.patterns {
+            if let Some(captures) = pattern.captures(key) {
+                let dimension = captures.name("dimension").or(captures.get(1)).or(captures.get(0));
+                return dimension.map(|dimension| dimension.as_str().to_string());
+            }
+        }
+
+        None
+    }
+
+    /// Splits the keys of a record into scores and keys that are neither fields nor scores.
+    fn scores<'a>(&self, data: &'a BTreeMap<String, serde_json::Value>) -> (Vec<RecordScore<'a>>, Vec<String>) {
+        let mut scores = Vec::new();
+        let mut unrecognized = Vec::new();
+        for (key, value) in data {
+            if FIELDS.contains(&key.as_str()) || self.score_keys.ignore.contains(key) {
+                continue;
+            }
+
+            if self.score_keys.nested.contains(key) && let Some(nested) = value.as_object() {
+                for (dimension, value) in nested {
+                    scores.push(RecordScore {key: format!("{}.{}", key, dimension), dimension: dimension.clone(), value});
+                }
+            } else if let Some(dimension) = self.score_dimension(key) {
+                scores.push(RecordScore {key: key.clone(), dimension, value});
+            } else {
+                unrecognized.push(key.clone());
+            }
+        }
+
+        (scores, unrecognized)
+    }
+
+    /// Reads an integer score, coercing floats and numeric strings when configured to, with the
+    /// transform applied. Missing tokens are read first, so a token such as `-1` is never taken as
+    /// a score.
+    fn score_value(&self, value: &serde_json::Value) -> Result<(ScoreValue, String), String> {
+        if let Some(reason) = self.missing.reason_json(value) {
+            return Ok((ScoreValue::Missing(reason), "missing token".into()));
+        }
+        if let Some(value) = value.as_i64() {
+            return Ok((ScoreValue::Integer(value), "none".into()));
+        }
+        if !self.score_keys.coerce {
+            return Err(format!("is not an integer: {}", value));
+        }
+
+        let number = match value {
+            serde_json::Value::Number(_) => ScoreValue::from_json_number(value),
+            serde_json::Value::String(text) => ScoreValue::parse_number(text),
+            _ => None,
+        };
+        let parse = if value.is_string() {"parse number, "} else {""};
+        match number {
+            Some(ScoreValue::Decimal(number)) => self.score_keys.rounding.apply(number)
+                .map(|number| (number, format!("{}round {}", parse, self.score_keys.rounding.name())))
+                .ok_or_else(|| format!("cannot be rounded ({}): {}", self.score_keys.rounding.name(), value)),
+            Some(number) => Ok((number, if value.is_string() {"parse number"} else {"none"}.into())),
+            None => Err(format!("is not numeric: {}", value)),
+        }
+    }
 }
 
 impl Provider for ProviderHandler {
     fn get_metadata(&self) -> BTreeMap<String, String> {
-        BTreeMap::from([
+        let mut metadata = BTreeMap::from([
             ("sourceProvider".to_string(), "provider_b".to_string()),
             ("sourceFormat".to_string(), "flat_key_value".to_string()),
             ("ingestedAt".to_string(), Utc::now().to_rfc3339(),),
             ("version".to_string(), "1.0".to_string()),
-        ])
+        ]);
+        if self.score_keys.coerce {
+            metadata.insert("scoreRounding".to_string(), self.score_keys.rounding.name().to_string());
+        }
+        metadata
     }  
 
     fn parse(&mut self, data: &str) -> Result<(), NormalizationError>  {
+        self.patterns = self.score_keys.patterns.iter()
+            .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+            .collect::<Result<_, _>>()
+            .map_err(|err| NormalizationError::Unknown(format!("Invalid score key pattern: {}", err)))?;
         match serde_json::from_str::<Vec<BTreeMap<String, serde_json::Value>>>(data) {
             Ok(result) => {
                 self.data = result;
@@ -58,22 +169,32 @@ impl Provider for ProviderHandler {
             if !ID.1(data) ||
                 !NAME.1(data) ||
                 !TYPE.1(data) ||
-                !NOTES.1(data) {
+                !NOTES.1(data) ||
+                !RECORD_ID.1(data) ||
+                !RECORD_STATUS.1(data) {
                 output.push(NormalizationError::Validate("Data is invalid".into(), index));
                 self.error_index.insert(index);
                 continue;
             }
 
-            for key in data.keys().filter(|x| x.starts_with("score_")) {
-                if !data[key].is_i64() {
-                    output.push(NormalizationError::Validate("Data is invalid".into(), index));
-                    self.error_index.insert(index);
-                    break;
-                }
-                if let None = data[key].as_i64() {
-                    output.push(NormalizationError::Validate("Data is invalid".into(), index));
-                    self.error_index.insert(index);
-                    break;
+            for key in self.score_keys.nested.iter().filter(|key| data.get(*key).is_some_and(|value| !value.is_object())) {
+                output.push(NormalizationError::Validate(format!("{} is not an object of scores", key), index));
+                self.error_index.insert(index);
+            }
+
+            let assessment_type = data[TYPE.0].as_str().unwrap_or_default();
+            let (scores, _) = self.scores(data);
+            for score in scores {
+                match self.score_value(score.value) {
+                    Ok((value, _)) => if let Some(issue) =
+                        self.ranges.check(RangeStage::Source, index, assessment_type, &score.dimension, &value, None) {
+                        output.push(NormalizationError::Range(issue));
+                        self.error_index.insert(index);
+                    },
+                    Err(message) => {
+                        output.push(NormalizationError::Validate(format!("{} {}", score.key, message), index));
+                        self.error_index.insert(index);
+                    },
                 }
             }
         }
@@ -82,7 +203,7 @@ impl Provider for ProviderHandler {
 
     fn convert(&self) -> Vec<NormalizeData> {
         let metadata = self.get_metadata();
-        let mut patients: BTreeMap<String, BTreeMap<String, NormalizeData>> = BTreeMap::new();
+        let mut patients: BTreeMap<String, BTreeMap<(String, Option<String>), NormalizeData>> = BTreeMap::new();
         for (index, data) in self.data.iter().enumerate() {
             if self.error_index.contains(&index) {
                 continue;
@@ -94,21 +215,39 @@ impl Provider for ProviderHandler {
             }
             if let Some(assessments) = patients.get_mut(&id) {
                 let assessment_type = data[TYPE.0].as_str().unwrap().to_string();
-                if !assessments.contains_key(&assessment_type) {
-                    assessments.insert(assessment_type.clone(), 
-                        NormalizeData::new(id, assessment_type.clone(), Utc::now(), metadata.clone()));
+                let record_id = data.get(RECORD_ID.0).and_then(|value| value.as_str()).map(|value| value.to_string());
+                let key = (assessment_type.clone(), record_id.clone());
+                if !assessments.contains_key(&key) {
+                    let mut normalized_data = NormalizeData::undated(id, assessment_type, metadata.clone());
+                    normalized_data.record_id = record_id;
+                    assessments.insert(key.clone(), normalized_data);
                 }
 
-                if let Some(normalized_data) = assessments.get_mut(&assessment_type) {
-                    for key in data.keys().filter(|x| x.starts_with("score_")) {
-                        let dimension = &key["score_".len()..];
-                        let value = data[key].as_i64().unwrap();
+                if let Some(normalized_data) = assessments.get_mut(&key) {
+                    let status = data.get(RECORD_STATUS.0).and_then(|value| value.as_str()).and_then(RecordStatus::parse);
+                    normalized_data.status = normalized_data.status.max(status.unwrap_or_default());
+                    let (scores, unrecognized) = self.scores(data);
+                    for score in scores {
+                        let (value, transform) = self.score_value(score.value).unwrap();
                         normalized_data.scores.push(NormalizeScore {
-                            dimension: dimension.to_string(),
+                            dimension: score.dimension,
                             value,
                             scale: "0-100".into(),
+                            derived: false,
+                            interpretation: None,
+                            lineage: Some(ScoreLineage::new(index, None, score.key, score.value.to_string(), transform)),
                         });
                     }
+
+                    // Keys that are neither fields nor scores are reported rather than dropped silently.
+                    if !unrecognized.is_empty() {
+                        let mut keys: BTreeSet<String> = normalized_data.metadata.get("unrecognizedKeys")
+                            .map(|keys| keys.split(',').map(|key| key.to_string()).collect())
+                            .unwrap_or_default();
+                        keys.extend(unrecognized);
+                        normalized_data.metadata.insert("unrecognizedKeys".to_string(),
+                            keys.into_iter().collect::<Vec<_>>().join(","));
+                    }
                 }
             }
         }
@@ -139,7 +278,7 @@ mod tests {
             "notes": "..."
         }]"#;
 
-        let mut handler = ProviderHandler::new();
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
         let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
         assert_eq!(provider.parse(json_str).is_ok(), true);
         assert_eq!(provider.validate(), NormalizationError::None);
@@ -147,4 +286,56 @@ mod tests {
         assert_eq!(converted.len(), 1);
         assert_eq!(converted[0].scores.len(), 2);
     }
+
+    #[test]
+    fn score_keys_test() {
+        let json_str = r#"[{
+            "patient_id": "P123b",
+            "patient_name": "last",
+            "assessment_type": "cognitive",
+            "score_memory": 85.5,
+            "attention_pct": "72",
+            "item_q3_raw": 4,
+            "item_q4_raw": -1,
+            "scores": {"processing": 61.2},
+            "clinician": "dr. x",
+            "notes": "..."
+        }, {
+            "patient_id": "P124b",
+            "patient_name": "last",
+            "assessment_type": "cognitive",
+            "score_memory": "high",
+            "notes": "..."
+        }]"#;
+        let score_keys = ScoreKeys {
+            suffixes: vec!["_pct".into()],
+            patterns: vec![r"item_(?P<dimension>q\d+)_raw".into()],
+            coerce: true,
+            rounding: crate::config::Rounding::Floor,
+            ..ScoreKeys::default()
+        };
+
+        let missing = MissingTokens(BTreeMap::from([("-1".to_string(), crate::model::MissingReason::Refused)]));
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {score_keys, missing, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(json_str).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::Validate("score_memory is not numeric: \"high\"".into(), 1),
+        ]));
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 1);
+        let scores: Vec<(&str, ScoreValue)> = converted[0].scores.iter()
+            .map(|score| (score.dimension.as_str(), score.value.clone()))
+            .collect();
+        assert_eq!(scores, vec![
+            ("attention", ScoreValue::Integer(72)),
+            ("q3", ScoreValue::Integer(4)),
+            ("q4", ScoreValue::Missing(crate::model::MissingReason::Refused)),
+            ("memory", ScoreValue::Integer(85)),
+            ("processing", ScoreValue::Integer(61)),
+        ]);
+        assert_eq!(converted[0].metadata["unrecognizedKeys"], "clinician");
+        assert_eq!(converted[0].metadata["scoreRounding"], "floor");
+    }
 }
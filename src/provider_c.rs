@@ -2,9 +2,11 @@
 use serde::{Serialize, Deserialize, Deserializer, de::Error};
 use std::collections::{BTreeMap, HashSet};
 use std::vec::Vec;
-use chrono::{DateTime, NaiveDate, FixedOffset, Utc};
+use chrono::{DateTime, NaiveDate, NaiveTime, FixedOffset, Utc};
 
-use crate::model::{NormalizationError, NormalizeData, NormalizeScore, Provider};
+use crate::config::{CsvDialect, MissingTokens, SourceConfig, WideLayout};
+use crate::ranges::{Range, RangeCatalog, RangeStage, Scaling};
+use crate::model::{NormalizationError, NormalizeData, NormalizeScore, Provider, RecordStatus, ScoreLineage, ScoreValue};
 
 fn parse_assessment_date(date: &str) -> Option<NaiveDate>
 {
@@ -15,7 +17,26 @@ fn parse_assessment_date(date: &str) -> Option<NaiveDate>
     None
 }
 
+/// Returns the raw text the reader consumed between two positions, without the line ending.
+fn raw_row(data: &str, start: &csv::Position, end: &csv::Position) -> String {
+    data.get(start.byte() as usize..end.byte() as usize)
+        .unwrap_or_default()
+        .trim_matches(|c| c == '\r' || c == '\n')
+        .to_string()
+}
+
+/// Parses a metric value, keeping the decimal places of fractional values.
+fn parse_metric_value(value: &str) -> Option<ScoreValue> {
+    ScoreValue::parse_number(value)
+}
+
+/// Scale of metric values without a reference range; they are mapped onto their target range,
+/// by default 0-100.
+pub const SOURCE_RANGE: Range = Range {min: 0.0, max: 10.0};
+
 type ValidationFunc = fn(&BTreeMap<String, String>) -> bool;
+/// Assessment type, record id and date of the assessment a row belongs to.
+type RowKey = (String, Option<String>, NaiveDate);
 const ID: (&str, ValidationFunc) = 
     ("patient_id", |data| data.contains_key(ID.0) && data[ID.0].len() > 0);
 const DATE: (&str, ValidationFunc) = 
@@ -24,29 +45,99 @@ const DATE: (&str, ValidationFunc) =
 const METRIC: (&str, ValidationFunc) = 
     ("metric_name", |data| data.contains_key(METRIC.0) && data[METRIC.0].len() > 0);
 const VALUE: (&str, ValidationFunc) = 
-    ("metric_value", |data| {
-        if !data.contains_key(VALUE.0) {
-            return false;
-        }
-        if let Ok(val) = data[VALUE.0].parse::<i64>() {
-            return val >= 0 && val <= 100;
-        }
-
-        return false;
-    });
+    ("metric_value", |data| data.contains_key(VALUE.0) && parse_metric_value(&data[VALUE.0]).is_some());
 const CATEGORY: (&str, ValidationFunc) = 
     ("category", |data| data.contains_key(CATEGORY.0) && data[CATEGORY.0].len() > 0);
+/// Optional identifier of the assessment, shared by its amended versions.
+const RECORD_ID: &str = "record_id";
+/// Optional `amended` or `retracted` marker, blank for original assessments.
+const RECORD_STATUS: (&str, ValidationFunc) =
+    ("record_status", |data| data.get(RECORD_STATUS.0).is_none_or(|value| RecordStatus::parse(value).is_some()));
+
+/// A metric value, or a missing value when it is one of the source's missing tokens.
+fn metric_value(missing: &MissingTokens, value: &str) -> Option<ScoreValue> {
+    match missing.reason(value) {
+        Some(reason) => Some(ScoreValue::Missing(reason)),
+        None => parse_metric_value(value),
+    }
+}
+
+/// Lineage of a metric value read from `column` of a row, which is scaled unless it is a missing
+/// token.
+fn metric_lineage(missing: &MissingTokens, scaling: &Scaling, index: usize, line: Option<usize>, column: &str, value: &str) -> ScoreLineage {
+    let transform = if missing.reason(value).is_some() {"missing token".to_string()} else {scaling.transform()};
+    ScoreLineage::new(index, line, column, value, transform)
+}
+
+/// Checks a source value against its range, and that it can be scaled onto its target range.
+fn check_metric(ranges: &RangeCatalog, index: usize, assessment_type: &str, dimension: &str, value: &ScoreValue) -> Option<NormalizationError> {
+    if let Some(issue) = ranges.check(RangeStage::Source, index, assessment_type, dimension, value, Some(SOURCE_RANGE)) {
+        return Some(NormalizationError::Range(issue));
+    }
+    if ranges.scaling(assessment_type, dimension, SOURCE_RANGE).apply(value).is_none() {
+        return Some(NormalizationError::Validate(format!("Metric {} cannot be scaled: {}", dimension, value), index));
+    }
+
+    None
+}
+
+/// Assessment type of a wide row, from its `category` column or the configured default.
+fn wide_assessment_type(wide: &WideLayout, data: &BTreeMap<String, String>) -> Option<String> {
+    match data.get(CATEGORY.0) {
+        Some(category) if !category.is_empty() => Some(category.clone()),
+        _ => wide.assessment_type.clone(),
+    }
+}
+
+/// Checks a wide row, where each mapped metric column is either blank, a missing token or a valid
+/// metric value.
+fn validate_wide_row(wide: &WideLayout, missing: &MissingTokens, data: &BTreeMap<String, String>) -> Result<(), String> {
+    if !ID.1(data) || !DATE.1(data) || !RECORD_STATUS.1(data) || wide_assessment_type(wide, data).is_none() {
+        return Err("Data is invalid".into());
+    }
+
+    let mut present = 0;
+    for column in wide.metrics.keys() {
+        match data.get(column).map(|value| value.as_str()) {
+            Some(value) if metric_value(missing, value).is_some() => present += 1,
+            None | Some("") => {},
+            Some(value) => return Err(format!("Invalid value for {}: {}", column, value)),
+        }
+    }
+
+    if present == 0 {
+        return Err("Row has no metric values".into());
+    }
+
+    Ok(())
+}
+
+/// Column order assumed for headerless files without a configured mapping.
+const COLUMNS: [&str; 5] = [ID.0, DATE.0, METRIC.0, VALUE.0, CATEGORY.0];
 
 pub struct ProviderHandler {
+    pub dialect: CsvDialect,
+    pub wide: Option<WideLayout>,
+    pub missing: MissingTokens,
+    pub ranges: RangeCatalog,
     pub data: Vec<BTreeMap<String, String>>,
+    /// Line of each row of `data` in the file.
+    pub lines: Vec<usize>,
     pub error_index: HashSet<usize>,
+    pub parse_errors: Vec<NormalizationError>,
 }
 
 impl ProviderHandler {
-    pub fn new() -> Self {
+    pub fn with_source(source: SourceConfig) -> Self {
         Self {
+            dialect: source.csv,
+            wide: source.wide,
+            missing: source.missing,
+            ranges: source.ranges,
             data: Vec::new(),
+            lines: Vec::new(),
             error_index: HashSet::new(),
+            parse_errors: Vec::new(),
         }
     }
 
@@ -57,61 +148,108 @@ impl Provider for ProviderHandler {
     fn get_metadata(&self) -> BTreeMap<String, String> {
         BTreeMap::from([
             ("sourceProvider".to_string(), "provider_c".to_string()),
-            ("sourceFormat".to_string(), "csv".to_string()),
+            ("sourceFormat".to_string(), if self.wide.is_some() {"wide_csv"} else {"csv"}.to_string()),
             ("ingestedAt".to_string(), Utc::now().to_rfc3339(),),
             ("version".to_string(), "1.0".to_string()),
         ])
     }  
 
     fn parse(&mut self, data: &str) -> Result<(), NormalizationError>  {
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(true)
+        let data = self.dialect.prepare(data);
+        let mut rdr = self.dialect.reader_builder()?
             .from_reader(data.as_bytes());
     
-        let mut headers: Vec<String> = Vec::new();
-        let maybe_headers = rdr.headers();
-        if let Ok(rdr_headers) = maybe_headers {
-            for value in rdr_headers {
-                headers.push(value.into());
+        let headers: Vec<String> = if !self.dialect.has_headers {
+            if self.dialect.columns.is_empty() {
+                COLUMNS.iter().map(|column| column.to_string()).collect()
+            } else {
+                self.dialect.columns.clone()
             }
+        } else if let Ok(rdr_headers) = rdr.headers() {
+            rdr_headers.iter().map(|value| self.dialect.canonical_header(value)).collect()
         } else {
             return Err(NormalizationError::Parse("Missing header row".into()));
-        }
+        };
 
-
-        let mut result = rdr.records().next();
         let header_count = headers.len();
-        while result.is_some() {
-            if let Ok(values) = result.unwrap() {
-                if values.len() != header_count {
-                    return Err(NormalizationError::Parse("Field count is not equal to header count".into()));
-                }
+        let mut values = csv::StringRecord::new();
+        loop {
+            let start = rdr.position().clone();
+            match rdr.read_record(&mut values) {
+                Ok(false) => break,
+                Ok(true) => {
+                    let line = values.position().map_or(start.line(), |pos| pos.line()) as usize;
+                    if values.len() != header_count {
+                        self.parse_errors.push(NormalizationError::ParseRow(
+                            format!("Field count {} is not equal to header count {}", values.len(), header_count),
+                            line,
+                            raw_row(data, &start, rdr.position())));
+                        continue;
+                    }
 
-                let mut row = BTreeMap::new();
-                let mut index = 0;
-                for value in &values {
-                    row.insert(headers[index].clone(), value.trim().into());
-                    index += 1;
-                }
+                    let mut row = BTreeMap::new();
+                    for (header, value) in headers.iter().zip(values.iter()) {
+                        row.insert(header.clone(), value.trim().into());
+                    }
 
-                self.data.push(row);
+                    self.data.push(row);
+                    self.lines.push(line);
+                },
+                Err(err) => {
+                    let line = err.position().map_or(start.line(), |pos| pos.line()) as usize;
+                    self.parse_errors.push(NormalizationError::ParseRow(
+                        err.to_string(),
+                        line,
+                        raw_row(data, &start, rdr.position())));
+                    if rdr.is_done() {
+                        break;
+                    }
+                },
             }
-            result = rdr.records().next();
         }
 
         Ok(())
     }
 
     fn validate(&mut self) -> NormalizationError {
-        let mut output = Vec::new();
+        let mut output = self.parse_errors.clone();
+        if let Some(wide) = &self.wide {
+            for (index, data) in self.data.iter().enumerate() {
+                if let Err(message) = validate_wide_row(wide, &self.missing, data) {
+                    output.push(NormalizationError::Validate(message, index));
+                    self.error_index.insert(index);
+                    continue;
+                }
+
+                let assessment_type = wide_assessment_type(wide, data).unwrap_or_default();
+                for (column, dimension) in &wide.metrics {
+                    let value = data.get(column).and_then(|value| metric_value(&self.missing, value));
+                    let issue = value.and_then(|value| check_metric(&self.ranges, index, &assessment_type, dimension, &value));
+                    if let Some(issue) = issue {
+                        output.push(issue);
+                        self.error_index.insert(index);
+                    }
+                }
+            }
+            return if output.is_empty() {NormalizationError::None} else {NormalizationError::Aggregate(output)};
+        }
+
         for (index, data) in self.data.iter().enumerate() {
             if !ID.1(data) ||
                 !DATE.1(data) ||
                 !METRIC.1(data) ||
-                !VALUE.1(data) ||
-                !CATEGORY.1(data) {
+                (!VALUE.1(data) && data.get(VALUE.0).and_then(|value| self.missing.reason(value)).is_none()) ||
+                !CATEGORY.1(data) ||
+                !RECORD_STATUS.1(data) {
                 output.push(NormalizationError::Validate("Data is invalid".into(), index));
                 self.error_index.insert(index);
+                continue;
+            }
+
+            let value = metric_value(&self.missing, &data[VALUE.0]).unwrap();
+            if let Some(issue) = check_metric(&self.ranges, index, &data[CATEGORY.0], &data[METRIC.0], &value) {
+                output.push(issue);
+                self.error_index.insert(index);
             }
         }
         if output.len() > 0 {NormalizationError::Aggregate(output)} else {NormalizationError::None}
@@ -119,7 +257,7 @@ impl Provider for ProviderHandler {
 
     fn convert(&self) -> Vec<NormalizeData> {
         let metadata = self.get_metadata();
-        let mut patients: BTreeMap<String, BTreeMap<String, NormalizeData>> = BTreeMap::new();
+        let mut patients: BTreeMap<String, BTreeMap<RowKey, NormalizeData>> = BTreeMap::new();
         for (index, data) in self.data.iter().enumerate() {
             if self.error_index.contains(&index) {
                 continue;
@@ -130,19 +268,50 @@ impl Provider for ProviderHandler {
                 patients.insert(id.clone(), BTreeMap::new());
             }
             if let Some(assessments) = patients.get_mut(&id) {
-                let assessment_type = data[CATEGORY.0].clone();
-                if !assessments.contains_key(&assessment_type) {
-                    assessments.insert(assessment_type.clone(), 
-                        NormalizeData::new(id, assessment_type.clone(), Utc::now(), metadata.clone()));
+                let assessment_type = match &self.wide {
+                    Some(wide) => wide_assessment_type(wide, data).unwrap_or_default(),
+                    None => data[CATEGORY.0].clone(),
+                };
+                let record_id = data.get(RECORD_ID).filter(|record_id| !record_id.is_empty()).cloned();
+                // Rows of the same assessment on different dates are separate assessments.
+                let date = parse_assessment_date(&data[DATE.0]).unwrap();
+                let key = (assessment_type.clone(), record_id.clone(), date);
+                if !assessments.contains_key(&key) {
+                    let mut normalized_data = NormalizeData::new(id, assessment_type, date.and_time(NaiveTime::MIN).and_utc(), metadata.clone());
+                    normalized_data.record_id = record_id;
+                    assessments.insert(key.clone(), normalized_data);
+                }
+                if let Some(normalized_data) = assessments.get_mut(&key) {
+                    let status = data.get(RECORD_STATUS.0).and_then(|status| RecordStatus::parse(status));
+                    normalized_data.status = normalized_data.status.max(status.unwrap_or_default());
                 }
 
-                if let Some(normalized_data) = assessments.get_mut(&assessment_type) {
+                if let (Some(normalized_data), Some(wide)) = (assessments.get_mut(&key), &self.wide) {
+                    for (column, dimension) in &wide.metrics {
+                        // Blank cells that are not a missing token produce no score.
+                        if let Some(value) = data.get(column).and_then(|value| metric_value(&self.missing, value)) {
+                            let scaling = self.ranges.scaling(&normalized_data.assessmentType, dimension, SOURCE_RANGE);
+                            normalized_data.scores.push(NormalizeScore {
+                                dimension: dimension.clone(),
+                                value: scaling.apply(&value).unwrap(),
+                                scale: scaling.target.scale(),
+                                derived: false,
+                                interpretation: None,
+                                lineage: Some(metric_lineage(&self.missing, &scaling, index, self.lines.get(index).copied(), column, &data[column])),
+                            });
+                        }
+                    }
+                } else if let Some(normalized_data) = assessments.get_mut(&key) {
                     let dimension = data[METRIC.0].clone();
-                    let value = data[VALUE.0].parse::<i64>().unwrap();
+                    let value = metric_value(&self.missing, &data[VALUE.0]).unwrap();
+                    let scaling = self.ranges.scaling(&normalized_data.assessmentType, &dimension, SOURCE_RANGE);
                     normalized_data.scores.push(NormalizeScore {
                         dimension: dimension.to_string(),
-                        value: value * 10,
-                        scale: "0-100".into(),
+                        value: scaling.apply(&value).unwrap(),
+                        scale: scaling.target.scale(),
+                        derived: false,
+                        interpretation: None,
+                        lineage: Some(metric_lineage(&self.missing, &scaling, index, self.lines.get(index).copied(), VALUE.0, &data[VALUE.0])),
                     });
                 }
             }
@@ -164,6 +333,7 @@ impl Provider for ProviderHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::MissingReason;
 
     #[test]
     fn provider_c_test() {
@@ -172,7 +342,7 @@ mod tests {
             P123c,2024-10-15,social_engagement,4,behavioral
             P124c,2024-10-15,social_engagement,4,behavioral";
 
-        let mut handler = ProviderHandler::new();
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
         let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
         assert_eq!(provider.parse(csv_c).is_ok(), true);
         assert_eq!(provider.validate(), NormalizationError::None);
@@ -181,4 +351,163 @@ mod tests {
         assert_eq!(converted[0].scores.len(), 2);
         
     }
+
+    #[test]
+    fn provider_c_malformed_rows_test() {
+        let csv_c = "patient_id,assessment_date,metric_name,metric_value,category\n\
+            P123c,2024-10-15,attention_span,6,behavioral\n\
+            P123c,2024-10-15,social_engagement\n\
+            P124c,2024-10-15,social_engagement,4,behavioral";
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(csv_c).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::ParseRow(
+                "Field count 3 is not equal to header count 5".into(),
+                3,
+                "P123c,2024-10-15,social_engagement".into()),
+        ]));
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[1].scores[0].lineage, Some(ScoreLineage::new(1, Some(4), "metric_value", "4", "x10")));
+    }
+
+    #[test]
+    fn provider_c_dialect_test() {
+        let csv_c = "\u{feff}PatientID;Assessment_Date;Metric_Name;Metric_Value;Category\n\
+            # exported from clinic spreadsheet\n\
+            P123c;2024-10-15;attention_span;6;behavioral\n\
+            P123c;2024-10-15;social_engagement;4;behavioral";
+
+        let dialect = CsvDialect {
+            delimiter: ';',
+            comment: Some('#'),
+            case_insensitive_headers: true,
+            header_aliases: BTreeMap::from([("patientid".to_string(), "patient_id".to_string())]),
+            ..Default::default()
+        };
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {csv: dialect, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(csv_c).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::None);
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].scores.len(), 2);
+    }
+
+    #[test]
+    fn provider_c_headerless_test() {
+        let csv_c = "behavioral,P123c,2024-10-15,attention_span,6";
+
+        let dialect = CsvDialect {
+            has_headers: false,
+            columns: vec!["category".into(), "patient_id".into(), "assessment_date".into(),
+                "metric_name".into(), "metric_value".into()],
+            ..Default::default()
+        };
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {csv: dialect, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(csv_c).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::None);
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].assessmentType, "behavioral");
+    }
+
+    #[test]
+    fn provider_c_wide_test() {
+        let csv_c = "patient_id,assessment_date,attention_span,social_engagement\n\
+            P123c,2024-10-15,6.5,4\n\
+            P124c,2024-10-15,,4\n\
+            P125c,2024-10-15,,";
+
+        let wide = WideLayout {
+            metrics: BTreeMap::from([
+                ("attention_span".to_string(), "attention".to_string()),
+                ("social_engagement".to_string(), "social".to_string()),
+            ]),
+            assessment_type: Some("behavioral".into()),
+        };
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {wide: Some(wide), ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(csv_c).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::Validate("Row has no metric values".into(), 2),
+        ]));
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].scores.len(), 2);
+        assert_eq!(converted[0].scores[0].value, ScoreValue::Decimal(crate::model::Decimal {units: 650, precision: 1}));
+        assert_eq!(converted[1].scores.len(), 1);
+        assert_eq!(converted[1].scores[0].dimension, "social");
+    }
+
+    #[test]
+    fn provider_c_missing_test() {
+        let csv_c = "patient_id,assessment_date,metric_name,metric_value,category\n\
+            P123c,2024-10-15,attention_span,6,behavioral\n\
+            P123c,2024-10-15,social_engagement,,behavioral\n\
+            P123c,2024-10-15,mood,NA,behavioral\n\
+            P123c,2024-10-15,sleep,999,behavioral\n\
+            P123c,2024-10-15,appetite,n/a,behavioral";
+
+        let missing = MissingTokens(BTreeMap::from([
+            ("".to_string(), MissingReason::Unknown),
+            ("NA".to_string(), MissingReason::NotApplicable),
+            ("999".to_string(), MissingReason::NotAdministered),
+        ]));
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {missing, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(csv_c).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::Validate("Data is invalid".into(), 4),
+        ]));
+        let converted = provider.convert();
+        let values: Vec<ScoreValue> = converted[0].scores.iter().map(|score| score.value.clone()).collect();
+        assert_eq!(values, vec![
+            ScoreValue::Integer(60),
+            ScoreValue::Missing(MissingReason::Unknown),
+            ScoreValue::Missing(MissingReason::NotApplicable),
+            ScoreValue::Missing(MissingReason::NotAdministered),
+        ]);
+    }
+
+    #[test]
+    fn provider_c_range_test() {
+        let csv_c = "patient_id,assessment_date,metric_name,metric_value,category\n\
+            P123c,2024-10-15,attention_span,11,behavioral\n\
+            P123c,2024-10-15,percentile,87.5,behavioral";
+
+        let ranges = RangeCatalog(vec![crate::ranges::RangeEntry {
+            assessment_type: "behavioral".into(),
+            dimension: "percentile".into(),
+            source: Some(Range::new(0.0, 100.0)),
+            target: None,
+        }]);
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {ranges, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(csv_c).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::Range(crate::ranges::RangeIssue {
+                stage: RangeStage::Source,
+                record: 0,
+                assessment_type: "behavioral".into(),
+                dimension: "attention_span".into(),
+                value: "11".into(),
+                range: "0..10".into(),
+            }),
+        ]));
+        let converted = provider.convert();
+        assert_eq!(converted[0].scores.len(), 1);
+        assert_eq!(converted[0].scores[0].value, ScoreValue::Decimal(crate::model::Decimal {units: 875, precision: 1}));
+        assert_eq!(converted[0].scores[0].scale, "0-100");
+        assert_eq!(converted[0].scores[0].lineage.as_ref().unwrap().transform, "none");
+        assert!(RangeCatalog::default().check_normalized(&converted).is_empty());
+    }
 }
@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashSet};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::Value;
+
+use crate::config::{MissingTokens, SourceConfig};
+use crate::model::{Demographics, MissingReason, Sex, NormalizationError, NormalizeData, NormalizeScore, Provider, RecordStatus, ScoreLineage, ScoreValue};
+
+/// Parses FHIR `date` and `dateTime` values, which may be a full timestamp or just a date.
+fn parse_fhir_date(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        return Some(date_time.with_timezone(&Utc));
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0).map(|date| date.and_utc())
+}
+
+/// Name of a CodeableConcept: its text, else the first coding's display or code.
+fn concept_name(concept: &Value) -> Option<String> {
+    if let Some(text) = concept["text"].as_str() {
+        return Some(text.to_string());
+    }
+
+    let coding = &concept["coding"][0];
+    coding["display"].as_str().or(coding["code"].as_str()).map(|name| name.to_string())
+}
+
+/// Maps a FHIR `dataAbsentReason` code onto a missing value reason.
+fn absent_reason(concept: &Value) -> Option<MissingReason> {
+    let code = concept["coding"][0]["code"].as_str()?;
+    Some(match code {
+        "asked-declined" => MissingReason::Refused,
+        "not-applicable" => MissingReason::NotApplicable,
+        "not-asked" | "not-performed" => MissingReason::NotAdministered,
+        _ => MissingReason::Unknown,
+    })
+}
+
+/// The value of an Observation, component or questionnaire answer: numeric, coded, text, or
+/// missing when it has a `dataAbsentReason` or its value is one of the source's missing tokens.
+fn score_value(missing: &MissingTokens, value: &Value) -> Option<ScoreValue> {
+    if let Some(reason) = absent_reason(&value["dataAbsentReason"]) {
+        return Some(ScoreValue::Missing(reason));
+    }
+    let token = [&value["valueQuantity"]["value"], &value["valueInteger"], &value["valueDecimal"], &value["valueString"]]
+        .into_iter()
+        .find_map(|value| if value.is_null() {None} else {missing.reason_json(value)});
+    if let Some(reason) = token {
+        return Some(ScoreValue::Missing(reason));
+    }
+
+    let number = [&value["valueQuantity"]["value"], &value["valueInteger"], &value["valueDecimal"]].into_iter()
+        .find(|value| value.is_number());
+    if let Some(number) = number {
+        return ScoreValue::from_json_number(number);
+    }
+
+    let coding = &value["valueCoding"];
+    if let Some(code) = coding["display"].as_str().or(coding["code"].as_str()) {
+        return Some(ScoreValue::Category(code.to_string()));
+    }
+    if value["valueCodeableConcept"].is_object() {
+        return concept_name(&value["valueCodeableConcept"]).map(ScoreValue::Category);
+    }
+
+    value["valueString"].as_str().map(|text| ScoreValue::Text(text.to_string()))
+}
+
+/// The element `score_value` reads a value from, with the transform it applies.
+fn value_source<'a>(missing: &MissingTokens, value: &'a Value) -> Option<(&'static str, &'a Value, &'static str)> {
+    if absent_reason(&value["dataAbsentReason"]).is_some() {
+        return Some(("dataAbsentReason", &value["dataAbsentReason"], "data absent reason"));
+    }
+    let elements = [
+        ("valueQuantity.value", &value["valueQuantity"]["value"]),
+        ("valueInteger", &value["valueInteger"]),
+        ("valueDecimal", &value["valueDecimal"]),
+        ("valueString", &value["valueString"]),
+    ];
+    if let Some((field, element)) = elements.iter().find(|(_, element)| !element.is_null() && missing.reason_json(element).is_some()) {
+        return Some((field, element, "missing token"));
+    }
+    if let Some((field, element)) = elements[..3].iter().find(|(_, element)| element.is_number()) {
+        return Some((field, element, "none"));
+    }
+
+    [("valueCoding", "code display"), ("valueCodeableConcept", "concept text")].into_iter()
+        .find(|(field, _)| value[*field].is_object())
+        .map(|(field, transform)| (field, &value[field], transform))
+        .or_else(|| value["valueString"].is_string().then_some(("valueString", &value["valueString"], "none")))
+}
+
+/// Reference range of an Observation or component as `low-high`.
+fn reference_range(value: &Value) -> Option<String> {
+    let range = &value["referenceRange"][0];
+    match (range["low"]["value"].as_f64(), range["high"]["value"].as_f64()) {
+        (Some(low), Some(high)) => Some(format!("{}-{}", low, high)),
+        _ => None,
+    }
+}
+
+/// Assessment type, resource id and date of the assessment a result belongs to.
+type ResourceKey = (String, Option<String>, DateTime<Utc>);
+
+/// A numeric result from a Bundle entry, with `path` locating it in the Bundle.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub path: String,
+    pub patient_id: String,
+    pub assessment_type: String,
+    pub assessment_date: String,
+    pub dimension: String,
+    pub value: Option<ScoreValue>,
+    pub reference_range: Option<String>,
+    /// Id of the resource holding the whole assessment, shared by its amended versions.
+    pub record_id: Option<String>,
+    /// From the resource `status`: `amended` and `corrected` are amendments, `entered-in-error`
+    /// is a retraction.
+    pub status: RecordStatus,
+    /// Where the value was read from, when it has one.
+    pub lineage: Option<ScoreLineage>,
+}
+
+/// Record id and status of a resource.
+fn record(resource: &Value) -> (Option<String>, RecordStatus) {
+    let record_id = resource["id"].as_str().filter(|id| !id.is_empty()).map(|id| id.to_string());
+    let status = resource["status"].as_str().and_then(RecordStatus::parse).unwrap_or_default();
+    (record_id, status)
+}
+
+pub struct ProviderHandler {
+    pub data: Vec<Observation>,
+    pub error_index: HashSet<usize>,
+    pub missing: MissingTokens,
+    patients: BTreeMap<String, String>,
+    /// Patient birth date and gender by patient id.
+    demographics: BTreeMap<String, Demographics>,
+}
+
+impl ProviderHandler {
+    pub fn with_source(source: SourceConfig) -> Self {
+        Self {
+            data: Vec::new(),
+            error_index: HashSet::new(),
+            missing: source.missing,
+            patients: BTreeMap::new(),
+            demographics: BTreeMap::new(),
+        }
+    }
+
+    /// Resolves a `subject` reference to a patient id. References to Patients in the Bundle use
+    /// the Patient's first identifier; others fall back to the id in the reference itself.
+    fn patient_id(&self, subject: &Value) -> String {
+        let reference = subject["reference"].as_str().unwrap_or_default();
+        if let Some(patient_id) = self.patients.get(reference) {
+            return patient_id.clone();
+        }
+        if let Some(value) = subject["identifier"]["value"].as_str() {
+            return value.to_string();
+        }
+
+        reference.rsplit('/').next().unwrap_or_default().to_string()
+    }
+
+    /// Lineage of the value of the next observation, read from `value` at `path`.
+    fn lineage(&self, path: &str, value: &Value) -> Option<ScoreLineage> {
+        let (field, raw, transform) = value_source(&self.missing, value)?;
+        Some(ScoreLineage::new(self.data.len(), None, format!("{}.{}", path, field), raw.to_string(), transform))
+    }
+
+    fn parse_observation(&mut self, path: &str, resource: &Value) {
+        let patient_id = self.patient_id(&resource["subject"]);
+        let assessment_date = resource["effectiveDateTime"].as_str()
+            .or(resource["issued"].as_str())
+            .unwrap_or_default()
+            .to_string();
+        let name = concept_name(&resource["code"]).unwrap_or_default();
+        let components = resource["component"].as_array().cloned().unwrap_or_default();
+        let (record_id, status) = record(resource);
+        if components.is_empty() {
+            // A single result is filed under its category, e.g. `survey`, with the other results of
+            // the category on the same date, so it has no record of its own and a result entered in
+            // error is left out.
+            if status == RecordStatus::Retracted {
+                return;
+            }
+            let category = resource["category"][0]["coding"][0]["code"].as_str().unwrap_or("observation");
+            self.data.push(Observation {
+                path: format!("{}.value", path),
+                patient_id,
+                assessment_type: category.to_string(),
+                assessment_date,
+                dimension: name,
+                value: score_value(&self.missing, resource),
+                reference_range: reference_range(resource),
+                record_id: None,
+                status,
+                lineage: self.lineage(path, resource),
+            });
+            return;
+        }
+
+        for (index, component) in components.iter().enumerate() {
+            let path = format!("{}.component[{}]", path, index);
+            self.data.push(Observation {
+                lineage: self.lineage(&path, component),
+                path,
+                patient_id: patient_id.clone(),
+                assessment_type: name.clone(),
+                assessment_date: assessment_date.clone(),
+                dimension: concept_name(&component["code"]).unwrap_or_default(),
+                value: score_value(&self.missing, component),
+                reference_range: reference_range(component),
+                record_id: record_id.clone(),
+                status,
+            });
+        }
+    }
+
+    fn parse_questionnaire_response(&mut self, path: &str, resource: &Value) {
+        let patient_id = self.patient_id(&resource["subject"]);
+        let assessment_type = resource["questionnaire"].as_str().unwrap_or_default()
+            .rsplit('/').next().unwrap_or_default()
+            .split('|').next().unwrap_or_default()
+            .to_string();
+        let assessment_date = resource["authored"].as_str().unwrap_or_default().to_string();
+        let (record_id, status) = record(resource);
+        let mut answers = Vec::new();
+        collect_answers(&format!("{}.item", path), &resource["item"], &mut answers);
+        for (item_path, item) in answers {
+            let path = format!("{}.answer[0]", item_path);
+            self.data.push(Observation {
+                lineage: self.lineage(&path, &item["answer"][0]),
+                path,
+                patient_id: patient_id.clone(),
+                assessment_type: assessment_type.clone(),
+                assessment_date: assessment_date.clone(),
+                dimension: item["linkId"].as_str().unwrap_or_default().to_string(),
+                value: score_value(&self.missing, &item["answer"][0]),
+                reference_range: None,
+                record_id: record_id.clone(),
+                status,
+            });
+        }
+    }
+}
+
+/// Collects answered questionnaire items in document order, including nested groups.
+fn collect_answers<'a>(path: &str, items: &'a Value, output: &mut Vec<(String, &'a Value)>) {
+    for (index, item) in items.as_array().map(|items| items.as_slice()).unwrap_or_default().iter().enumerate() {
+        let item_path = format!("{}[{}]", path, index);
+        if !item["answer"].is_null() {
+            output.push((item_path.clone(), item));
+        }
+        collect_answers(&format!("{}.item", item_path), &item["item"], output);
+    }
+}
+
+impl Provider for ProviderHandler {
+    fn get_metadata(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("sourceProvider".to_string(), "provider_fhir".to_string()),
+            ("sourceFormat".to_string(), "fhir_bundle".to_string()),
+            ("ingestedAt".to_string(), Utc::now().to_rfc3339(),),
+            ("version".to_string(), "1.0".to_string()),
+        ])
+    }
+
+    fn parse(&mut self, data: &str) -> Result<(), NormalizationError> {
+        let bundle: Value = serde_json::from_str(data).map_err(|err| NormalizationError::Parse(err.to_string()))?;
+        if bundle["resourceType"] != "Bundle" {
+            return Err(NormalizationError::Parse("Resource is not a FHIR Bundle".into()));
+        }
+
+        let entries = bundle["entry"].as_array().cloned().unwrap_or_default();
+        for entry in entries.iter().filter(|entry| entry["resource"]["resourceType"] == "Patient") {
+            let resource = &entry["resource"];
+            let id = resource["id"].as_str().unwrap_or_default();
+            let patient_id = resource["identifier"][0]["value"].as_str().unwrap_or(id).to_string();
+            self.demographics.insert(patient_id.clone(), Demographics {
+                date_of_birth: resource["birthDate"].as_str().and_then(parse_fhir_date).map(|date| date.date_naive()),
+                sex: resource["gender"].as_str().and_then(Sex::parse),
+            });
+            if let Some(full_url) = entry["fullUrl"].as_str() {
+                self.patients.insert(full_url.to_string(), patient_id.clone());
+            }
+            if !id.is_empty() {
+                self.patients.insert(format!("Patient/{}", id), patient_id);
+            }
+        }
+
+        for (index, entry) in entries.iter().enumerate() {
+            let path = format!("entry[{}].resource", index);
+            let resource = &entry["resource"];
+            match resource["resourceType"].as_str().unwrap_or_default() {
+                "Observation" => self.parse_observation(&path, resource),
+                "QuestionnaireResponse" => self.parse_questionnaire_response(&path, resource),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&mut self) -> NormalizationError {
+        let mut output = Vec::new();
+        for (index, data) in self.data.iter().enumerate() {
+            let message = if data.patient_id.is_empty() {
+                Some("has no resolvable subject")
+            } else if data.assessment_type.is_empty() {
+                Some("has no code or questionnaire")
+            } else if parse_fhir_date(&data.assessment_date).is_none() {
+                Some("has no valid effective or authored date")
+            } else if data.dimension.is_empty() {
+                Some("has no code or linkId")
+            } else if data.value.is_none() {
+                Some("has no numeric, coded or string value")
+            } else {
+                None
+            };
+
+            if let Some(message) = message {
+                output.push(NormalizationError::Validate(format!("{} {}", data.path, message), index));
+                self.error_index.insert(index);
+            }
+        }
+        if output.is_empty() {NormalizationError::None} else {NormalizationError::Aggregate(output)}
+    }
+
+    fn convert(&self) -> Vec<NormalizeData> {
+        let metadata = self.get_metadata();
+        let mut patients: BTreeMap<String, BTreeMap<ResourceKey, NormalizeData>> = BTreeMap::new();
+        for (index, data) in self.data.iter().enumerate() {
+            if self.error_index.contains(&index) {
+                continue;
+            }
+
+            let assessments = patients.entry(data.patient_id.clone()).or_default();
+            // Results of one category or resource on different dates are separate assessments.
+            let date = parse_fhir_date(&data.assessment_date).unwrap_or_else(Utc::now);
+            let normalized_data = assessments.entry((data.assessment_type.clone(), data.record_id.clone(), date)).or_insert_with(|| {
+                let mut normalized_data = NormalizeData::new(data.patient_id.clone(), data.assessment_type.clone(), date, metadata.clone());
+                normalized_data.demographics = self.demographics.get(&data.patient_id).cloned();
+                normalized_data.record_id = data.record_id.clone();
+                normalized_data
+            });
+            normalized_data.status = normalized_data.status.max(data.status);
+            normalized_data.scores.push(NormalizeScore {
+                dimension: data.dimension.clone(),
+                value: data.value.clone().unwrap(),
+                scale: data.reference_range.clone().unwrap_or_else(|| "0-100".into()),
+                derived: false,
+                interpretation: None,
+                lineage: data.lineage.clone(),
+            });
+        }
+
+        let mut output = Vec::new();
+        for (_, assessments) in patients {
+            for (_, assessment) in assessments {
+                output.push(assessment);
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_fhir_test() {
+        let bundle = include_str!("../test-data/data.fhir");
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(bundle).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::None);
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].patientId, "P123f");
+        assert_eq!(converted[0].assessmentType, "behavioral_screening");
+        assert_eq!(converted[0].scores.len(), 3);
+        assert_eq!(converted[0].scores[0].scale, "0-10");
+        assert_eq!(converted[0].scores[2].value, ScoreValue::Missing(MissingReason::Refused));
+        assert_eq!(converted[1].assessmentType, "cognitive");
+        assert_eq!(converted[1].assessmentDate, "2024-10-16T00:00:00+00:00");
+        assert_eq!(converted[1].scores.len(), 3);
+        assert_eq!(converted[1].scores[1].dimension, "processing");
+        assert_eq!(converted[1].scores[1].value, ScoreValue::Decimal(crate::model::Decimal {units: 725, precision: 1}));
+        assert_eq!(converted[1].scores[2].value, ScoreValue::Category("Not at all".into()));
+        let lineage = converted[1].scores[1].lineage.clone().unwrap();
+        assert_eq!((lineage.field.as_str(), lineage.raw.as_deref()), ("entry[2].resource.item[1].item[0].answer[0].valueDecimal", Some("72.5")));
+    }
+
+    #[test]
+    fn provider_fhir_dates_test() {
+        let observation = |date: &str, value: i64| serde_json::json!({"resource": {
+            "resourceType": "Observation",
+            "status": "final",
+            "category": [{"coding": [{"code": "survey"}]}],
+            "code": {"text": "anxiety"},
+            "subject": {"reference": "Patient/pat-1"},
+            "effectiveDateTime": date,
+            "valueInteger": value,
+        }});
+        let bundle = serde_json::json!({"resourceType": "Bundle", "entry": [
+            {"resource": {"resourceType": "Patient", "id": "pat-1", "identifier": [{"value": "P123f"}]}},
+            observation("2024-10-15T09:00:00Z", 7),
+            observation("2024-11-15T09:00:00Z", 5),
+        ]});
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        assert!(handler.parse(&bundle.to_string()).is_ok());
+        assert_eq!(handler.validate(), NormalizationError::None);
+        let converted = handler.convert();
+        let assessments: Vec<(&str, usize)> = converted.iter().map(|data| (data.assessmentDate.as_str(), data.scores.len())).collect();
+        assert_eq!(assessments, vec![("2024-10-15T09:00:00+00:00", 1), ("2024-11-15T09:00:00+00:00", 1)]);
+    }
+}
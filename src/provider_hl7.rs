@@ -0,0 +1,475 @@
+use std::collections::{BTreeMap, HashSet};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+use crate::config::{MissingTokens, SourceConfig};
+use crate::model::{Demographics, MissingReason, Sex, NormalizationError, NormalizeData, NormalizeScore, Provider, RecordStatus, ScoreLineage, ScoreValue};
+
+/// Separators declared in MSH-1 and MSH-2.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delimiters {
+    pub field: char,
+    pub component: char,
+    pub repetition: char,
+    pub escape: char,
+    pub subcomponent: char,
+}
+
+impl Delimiters {
+    fn from_msh(segment: &str) -> Option<Self> {
+        let mut chars = segment.chars().skip(3);
+        let field = chars.next()?;
+        let encoding: Vec<char> = chars.take_while(|c| *c != field).collect();
+        Some(Self {
+            field,
+            component: *encoding.first().unwrap_or(&'^'),
+            repetition: *encoding.get(1).unwrap_or(&'~'),
+            escape: *encoding.get(2).unwrap_or(&'\\'),
+            subcomponent: *encoding.get(3).unwrap_or(&'&'),
+        })
+    }
+
+    /// Resolves HL7 escape sequences such as `\F\`, `\S\`, `\T\`, `\R\`, `\E\`, `\Xhh\` and `\.br\`.
+    pub fn unescape(&self, value: &str) -> String {
+        let mut output = String::new();
+        let mut parts = value.split(self.escape);
+        output.push_str(parts.next().unwrap_or_default());
+        // Escape sequences sit between pairs of escape characters.
+        while let Some(sequence) = parts.next() {
+            let text = parts.next();
+            match sequence {
+                "F" => output.push(self.field),
+                "S" => output.push(self.component),
+                "T" => output.push(self.subcomponent),
+                "R" => output.push(self.repetition),
+                "E" => output.push(self.escape),
+                ".br" => output.push('\n'),
+                hex if hex.starts_with('X') => {
+                    let bytes: Vec<u8> = (1..hex.len()).step_by(2)
+                        .filter_map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+                        .collect();
+                    output.push_str(&String::from_utf8_lossy(&bytes));
+                },
+                _ => {},
+            }
+            match text {
+                Some(text) => output.push_str(text),
+                // An unterminated sequence is kept as it was sent.
+                None => {
+                    output.push(self.escape);
+                    output.push_str(sequence);
+                },
+            }
+        }
+
+        output
+    }
+}
+
+/// One segment split into fields, repetitions and components. Fields are numbered as in
+/// the HL7 spec, so `fields[3]` is `PID-3`; for MSH, `fields[1]` is the field separator.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    pub fields: Vec<Vec<Vec<String>>>,
+}
+
+impl Segment {
+    pub fn parse(raw: &str, delimiters: &Delimiters) -> Self {
+        let mut raw_fields: Vec<&str> = raw.split(delimiters.field).collect();
+        let name = raw_fields.first().map(|name| name.to_string()).unwrap_or_default();
+        let mut fields: Vec<Vec<Vec<String>>> = vec![vec![vec![name.clone()]]];
+        if name == "MSH" {
+            fields.push(vec![vec![delimiters.field.to_string()]]);
+            fields.push(vec![vec![raw_fields.get(1).unwrap_or(&"").to_string()]]);
+            raw_fields.drain(..2.min(raw_fields.len()));
+        } else if !raw_fields.is_empty() {
+            raw_fields.remove(0);
+        }
+
+        for field in raw_fields {
+            fields.push(field.split(delimiters.repetition)
+                .map(|repetition| repetition.split(delimiters.component)
+                    .map(|component| delimiters.unescape(component))
+                    .collect())
+                .collect());
+        }
+
+        Self { name, fields }
+    }
+
+    /// All repetitions of a field.
+    pub fn repetitions(&self, field: usize) -> &[Vec<String>] {
+        self.fields.get(field).map(|field| field.as_slice()).unwrap_or_default()
+    }
+
+    /// Component of the first repetition of a field, both numbered from 1.
+    pub fn get(&self, field: usize, component: usize) -> &str {
+        self.repetitions(field).first()
+            .and_then(|repetition| repetition.get(component - 1))
+            .map(|value| value.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Parses HL7 timestamps such as `20241015`, `202410150930` and `20241015093000+0100`.
+pub fn parse_hl7_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    let (local, offset) = match value.find(['+', '-']) {
+        Some(position) => (&value[..position], Some(&value[position..])),
+        None => (value, None),
+    };
+    let local = local.split('.').next().unwrap_or_default();
+    let date_time = match local.len() {
+        8 => NaiveDate::parse_from_str(local, "%Y%m%d").ok()?.and_hms_opt(0, 0, 0)?,
+        12 => NaiveDateTime::parse_from_str(local, "%Y%m%d%H%M").ok()?,
+        14 => NaiveDateTime::parse_from_str(local, "%Y%m%d%H%M%S").ok()?,
+        _ => return None,
+    };
+
+    match offset {
+        Some(offset) => DateTime::parse_from_str(
+                &format!("{}{}", date_time.format("%Y%m%d%H%M%S"), offset), "%Y%m%d%H%M%S%z")
+            .ok().map(|date_time| date_time.with_timezone(&Utc)),
+        None => Some(date_time.and_utc()),
+    }
+}
+
+/// A numeric or coded OBX result with the patient and order it belongs to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub line: usize,
+    pub patient_id: String,
+    pub assessment_type: String,
+    pub assessment_date: String,
+    pub dimension: String,
+    /// OBX-2 value type, `NM` or a coded type such as `CWE`.
+    pub value_type: String,
+    pub value: String,
+    /// Where `value` was read: `OBX-5`, or `OBX-5.2` for the text of a code.
+    pub value_field: &'static str,
+    pub reference_range: String,
+    /// OBX-11 result status.
+    pub status: String,
+    /// OBR-3 filler order number, or OBR-2 placer order number, shared by the amended versions
+    /// of the order.
+    pub record_id: Option<String>,
+    /// OBR-25 result status of the order.
+    pub order_status: String,
+    /// PID-7 date of birth and PID-8 sex.
+    pub demographics: Demographics,
+}
+
+/// OBR-4 service, order number and OBR-7 observation date of the assessment an observation
+/// belongs to.
+type OrderKey = (String, Option<String>, DateTime<Utc>);
+
+/// OBX-2 value types read as categorical results, using the text of the code when present.
+const CODED_TYPES: [&str; 3] = ["CE", "CWE", "CNE"];
+
+impl Observation {
+    /// OBX-11 `C` and OBR-25 `C` are corrections. OBX-11 `W` (posted as wrong) and `D` (deleted)
+    /// withdraw the result.
+    pub fn record_status(&self) -> RecordStatus {
+        match (self.status.as_str(), self.order_status.as_str()) {
+            ("W" | "D", _) => RecordStatus::Retracted,
+            ("C", _) | (_, "C") => RecordStatus::Amended,
+            _ => RecordStatus::Original,
+        }
+    }
+
+    /// The OBX value, or a missing value when the result status says it was not obtained or the
+    /// value is one of the source's missing tokens.
+    pub fn score_value(&self, missing: &MissingTokens) -> Option<ScoreValue> {
+        match self.status.as_str() {
+            "X" => return Some(ScoreValue::Missing(MissingReason::Unknown)),
+            "N" => return Some(ScoreValue::Missing(MissingReason::NotAdministered)),
+            _ => {},
+        }
+        if let Some(reason) = missing.reason(&self.value) {
+            return Some(ScoreValue::Missing(reason));
+        }
+        if CODED_TYPES.contains(&self.value_type.as_str()) {
+            return Some(ScoreValue::Category(self.value.clone())).filter(|_| !self.value.is_empty());
+        }
+
+        ScoreValue::parse_number(&self.value)
+    }
+
+    /// The transform `score_value` applies to the value.
+    pub fn transform(&self, missing: &MissingTokens) -> String {
+        if self.status == "X" || self.status == "N" {
+            format!("result status {}", self.status)
+        } else if missing.reason(&self.value).is_some() {
+            "missing token".into()
+        } else {
+            "none".into()
+        }
+    }
+}
+
+pub struct ProviderHandler {
+    pub data: Vec<Observation>,
+    pub error_index: HashSet<usize>,
+    pub parse_errors: Vec<NormalizationError>,
+    pub missing: MissingTokens,
+}
+
+impl ProviderHandler {
+    pub fn with_source(source: SourceConfig) -> Self {
+        Self {
+            data: Vec::new(),
+            error_index: HashSet::new(),
+            parse_errors: Vec::new(),
+            missing: source.missing,
+        }
+    }
+}
+
+impl Provider for ProviderHandler {
+    fn get_metadata(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("sourceProvider".to_string(), "provider_hl7".to_string()),
+            ("sourceFormat".to_string(), "hl7v2_oru_r01".to_string()),
+            ("ingestedAt".to_string(), Utc::now().to_rfc3339(),),
+            ("version".to_string(), "1.0".to_string()),
+        ])
+    }
+
+    fn parse(&mut self, data: &str) -> Result<(), NormalizationError> {
+        let segments: Vec<(usize, &str)> = data.split(['\r', '\n'])
+            .enumerate()
+            .map(|(index, segment)| (index + 1, segment.trim_start()))
+            .filter(|(_, segment)| !segment.is_empty())
+            .collect();
+        if !segments.first().is_some_and(|(_, segment)| segment.starts_with("MSH")) {
+            return Err(NormalizationError::Parse("Message does not start with an MSH segment".into()));
+        }
+
+        let mut delimiters = Delimiters::from_msh(segments[0].1)
+            .ok_or_else(|| NormalizationError::Parse("MSH segment has no field separator".into()))?;
+        let mut skip_message = false;
+        let mut patient_id = String::new();
+        let mut demographics = Demographics::default();
+        let mut assessment_type = String::new();
+        let mut assessment_date = String::new();
+        let mut record_id = None;
+        let mut order_status = String::new();
+        for (line, raw) in segments {
+            if raw.starts_with("MSH") {
+                delimiters = match Delimiters::from_msh(raw) {
+                    Some(delimiters) => delimiters,
+                    None => {
+                        self.parse_errors.push(NormalizationError::ParseRow(
+                            "MSH segment has no field separator".into(), line, raw.into()));
+                        skip_message = true;
+                        continue;
+                    },
+                };
+                let msh = Segment::parse(raw, &delimiters);
+                let message_type = format!("{}^{}", msh.get(9, 1), msh.get(9, 2));
+                skip_message = message_type != "ORU^R01";
+                if skip_message {
+                    self.parse_errors.push(NormalizationError::ParseRow(
+                        format!("Unsupported message type {}", message_type), line, raw.into()));
+                }
+                patient_id.clear();
+                assessment_type.clear();
+                assessment_date.clear();
+                record_id = None;
+                order_status.clear();
+                continue;
+            }
+            if skip_message {
+                continue;
+            }
+
+            let segment = Segment::parse(raw, &delimiters);
+            match segment.name.as_str() {
+                "PID" => {
+                    // PID-3 repeats; prefer the medical record number over other identifiers.
+                    let identifiers = segment.repetitions(3);
+                    patient_id = identifiers.iter()
+                        .find(|identifier| identifier.get(4).is_some_and(|kind| kind == "MR"))
+                        .or(identifiers.first())
+                        .and_then(|identifier| identifier.first())
+                        .cloned()
+                        .unwrap_or_default();
+                    demographics = Demographics {
+                        date_of_birth: parse_hl7_date(segment.get(7, 1)).map(|date| date.date_naive()),
+                        sex: Sex::parse(segment.get(8, 1)),
+                    };
+                },
+                "OBR" => {
+                    assessment_type = match segment.get(4, 2) {
+                        "" => segment.get(4, 1).to_string(),
+                        text => text.to_string(),
+                    };
+                    assessment_date = segment.get(7, 1).to_string();
+                    record_id = [segment.get(3, 1), segment.get(2, 1)].into_iter()
+                        .find(|id| !id.is_empty())
+                        .map(|id| id.to_string());
+                    order_status = segment.get(25, 1).to_string();
+                },
+                "OBX" if segment.get(2, 1) == "NM" || CODED_TYPES.contains(&segment.get(2, 1)) => {
+                    let value_type = segment.get(2, 1).to_string();
+                    let (value_field, value) = match segment.get(5, 2) {
+                        text if !text.is_empty() && value_type != "NM" => ("OBX-5.2", text),
+                        _ => ("OBX-5", segment.get(5, 1)),
+                    };
+                    self.data.push(Observation {
+                        line,
+                        patient_id: patient_id.clone(),
+                        assessment_type: assessment_type.clone(),
+                        assessment_date: assessment_date.clone(),
+                        dimension: match segment.get(3, 2) {
+                            "" => segment.get(3, 1).to_string(),
+                            text => text.to_string(),
+                        },
+                        value_type,
+                        value: value.trim().to_string(),
+                        value_field,
+                        reference_range: segment.get(7, 1).to_string(),
+                        status: segment.get(11, 1).to_string(),
+                        record_id: record_id.clone(),
+                        order_status: order_status.clone(),
+                        demographics: demographics.clone(),
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&mut self) -> NormalizationError {
+        let mut output = self.parse_errors.clone();
+        for (index, data) in self.data.iter().enumerate() {
+            let message = if data.patient_id.is_empty() {
+                Some("Observation has no PID-3 patient identifier")
+            } else if data.assessment_type.is_empty() {
+                Some("Observation has no OBR-4 service identifier")
+            } else if parse_hl7_date(&data.assessment_date).is_none() {
+                Some("Observation has no valid OBR-7 observation date")
+            } else if data.dimension.is_empty() {
+                Some("Observation has no OBX-3 identifier")
+            } else if data.record_status() != RecordStatus::Retracted && data.score_value(&self.missing).is_none() {
+                Some("Observation value is not a number or code")
+            } else {
+                None
+            };
+
+            if let Some(message) = message {
+                output.push(NormalizationError::Validate(format!("{} (line {})", message, data.line), index));
+                self.error_index.insert(index);
+            }
+        }
+        if output.is_empty() {NormalizationError::None} else {NormalizationError::Aggregate(output)}
+    }
+
+    fn convert(&self) -> Vec<NormalizeData> {
+        let metadata = self.get_metadata();
+        let mut patients: BTreeMap<String, BTreeMap<OrderKey, NormalizeData>> = BTreeMap::new();
+        for (index, data) in self.data.iter().enumerate() {
+            if self.error_index.contains(&index) {
+                continue;
+            }
+
+            let assessments = patients.entry(data.patient_id.clone()).or_default();
+            // Orders of one service and order number on different observation dates are separate
+            // assessments.
+            let date = parse_hl7_date(&data.assessment_date).unwrap_or_else(Utc::now);
+            let normalized_data = assessments.entry((data.assessment_type.clone(), data.record_id.clone(), date)).or_insert_with(|| {
+                let mut normalized_data = NormalizeData::new(data.patient_id.clone(), data.assessment_type.clone(), date, metadata.clone());
+                normalized_data.demographics = Some(data.demographics.clone());
+                normalized_data.record_id = data.record_id.clone();
+                normalized_data
+            });
+            // A withdrawn result amends its order, and an order whose results are all withdrawn is
+            // retracted.
+            if data.record_status() == RecordStatus::Retracted {
+                normalized_data.status = normalized_data.status.max(RecordStatus::Amended);
+                continue;
+            }
+            normalized_data.status = normalized_data.status.max(data.record_status());
+            normalized_data.scores.push(NormalizeScore {
+                dimension: data.dimension.clone(),
+                value: data.score_value(&self.missing).unwrap(),
+                scale: if data.reference_range.is_empty() {"0-100".into()} else {data.reference_range.clone()},
+                derived: false,
+                interpretation: None,
+                lineage: Some(ScoreLineage::new(index, Some(data.line), data.value_field, data.value.clone(), data.transform(&self.missing))),
+            });
+        }
+
+        let mut output = Vec::new();
+        for (_, assessments) in patients {
+            for (_, mut assessment) in assessments {
+                if assessment.scores.is_empty() {
+                    assessment.status = RecordStatus::Retracted;
+                }
+                output.push(assessment);
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_hl7_test() {
+        let message = include_str!("../test-data/data.hl7");
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(message).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::None);
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].patientId, "P123h");
+        assert_eq!(converted[0].assessmentType, "behavioral_screening");
+        assert_eq!(converted[0].assessmentDate, "2024-10-15T09:00:00+00:00");
+        assert_eq!(converted[0].demographics, Some(Demographics {
+            date_of_birth: NaiveDate::from_ymd_opt(2018, 2, 21),
+            sex: Some(Sex::Female),
+        }));
+        assert_eq!(converted[0].scores.len(), 5);
+        assert_eq!(converted[0].scores[0].scale, "0-10");
+        assert_eq!(converted[0].scores[2].value, ScoreValue::Decimal(crate::model::Decimal {units: 65, precision: 1}));
+        assert_eq!(converted[0].scores[3].value, ScoreValue::Category("Not at all".into()));
+        assert_eq!(converted[0].scores[4].value, ScoreValue::Missing(MissingReason::Unknown));
+        assert_eq!(converted[0].scores[3].lineage, Some(ScoreLineage::new(3, Some(8), "OBX-5.2", "Not at all", "none")));
+        assert_eq!(converted[0].scores[4].lineage.as_ref().map(|lineage| lineage.transform.as_str()), Some("result status X"));
+    }
+
+    #[test]
+    fn provider_hl7_dates_test() {
+        let message = "MSH|^~\\&|CLINIC_LAB|NORTH^1|NORMALIZE|HUB|20241115093000||ORU^R01|MSG0002|P|2.5.1\r\
+            PID|1||P123h^^^CLINIC^MR||Doe^Jane||20180221|F\r\
+            OBR|1|||BEH^behavioral_screening^L|||20241015090000\r\
+            OBX|1|NM|ANX^anxiety^L||7|score|0-10|N|||F\r\
+            OBR|2|||BEH^behavioral_screening^L|||20241115090000\r\
+            OBX|1|NM|ANX^anxiety^L||5|score|0-10|N|||F";
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        assert!(handler.parse(message).is_ok());
+        assert_eq!(handler.validate(), NormalizationError::None);
+        let converted = handler.convert();
+        let assessments: Vec<(&str, usize)> = converted.iter().map(|data| (data.assessmentDate.as_str(), data.scores.len())).collect();
+        assert_eq!(assessments, vec![("2024-10-15T09:00:00+00:00", 1), ("2024-11-15T09:00:00+00:00", 1)]);
+    }
+
+    #[test]
+    fn hl7_escape_test() {
+        let delimiters = Delimiters::from_msh("MSH|^~\\&|").unwrap();
+        assert_eq!(delimiters.unescape("Calm \\T\\ focused \\F\\ \\X41\\\\.br\\end"), "Calm & focused | A\nend");
+        let segment = Segment::parse("OBX|1|NM|ANX^anxiety~ALT^other||7", &delimiters);
+        assert_eq!(segment.get(3, 2), "anxiety");
+        assert_eq!(segment.repetitions(3)[1][0], "ALT");
+        assert_eq!(segment.get(5, 1), "7");
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use calamine::{Data, Reader, Xlsx};
+use chrono::{Days, NaiveDate};
+
+use crate::config::{SheetLayout, SourceConfig};
+use crate::model::{NormalizationError, NormalizeData, Provider};
+use crate::provider_c;
+
+/// Converts an Excel date serial, counted in days from 1899-12-30, to a date. Excel counts a
+/// 1900-02-29 that never was as serial 60, so earlier serials are one day later than the count
+/// suggests, and 60 itself is not a date.
+fn excel_serial_date(serial: f64) -> Option<NaiveDate> {
+    if !(1.0..2958466.0).contains(&serial) || serial.trunc() == 60.0 {
+        return None;
+    }
+
+    let days = if serial < 60.0 {serial.trunc() as u64 + 1} else {serial.trunc() as u64};
+    NaiveDate::from_ymd_opt(1899, 12, 30)?.checked_add_days(Days::new(days))
+}
+
+/// Renders a cell the way provider C would read it from a CSV file.
+fn cell_text(cell: &Data, is_date: bool) -> Result<String, String> {
+    let number = match cell {
+        Data::Empty => return Ok(String::new()),
+        Data::String(value) => return Ok(value.trim().to_string()),
+        Data::Bool(value) => return Ok(value.to_string()),
+        // Date columns keep only the date of ISO date-times, as CSV files would give it.
+        Data::DateTimeIso(value) if is_date => return Ok(value.split_once('T').map_or(value.as_str(), |(date, _)| date).to_string()),
+        Data::DateTimeIso(value) | Data::DurationIso(value) => return Ok(value.clone()),
+        Data::DateTime(value) => match value.as_datetime() {
+            Some(date_time) if value.is_datetime() => return Ok(date_time.format("%Y-%m-%d").to_string()),
+            _ => value.as_f64(),
+        },
+        Data::Error(err) => return Err(format!("Cell error {}", err)),
+        Data::Int(value) => *value as f64,
+        Data::Float(value) => *value,
+    };
+
+    if is_date {
+        return excel_serial_date(number)
+            .map(|date| date.format("%Y-%m-%d").to_string())
+            .ok_or_else(|| format!("Invalid date serial {}", number));
+    }
+
+    if number.fract() == 0.0 {
+        Ok(format!("{}", number as i64))
+    } else {
+        Ok(number.to_string())
+    }
+}
+
+/// Reads `.xlsx` workbooks and hands their rows to provider C's validation and conversion.
+pub struct ProviderHandler {
+    pub layout: SheetLayout,
+    pub rows: provider_c::ProviderHandler,
+}
+
+impl ProviderHandler {
+    pub fn with_source(source: SourceConfig) -> Self {
+        Self {
+            layout: source.xlsx.clone(),
+            rows: provider_c::ProviderHandler::with_source(source),
+        }
+    }
+
+    fn canonical_header(&self, header: &str) -> String {
+        let header = header.trim();
+        self.layout.columns.get(header).cloned().unwrap_or_else(|| header.to_string())
+    }
+}
+
+impl Provider for ProviderHandler {
+    fn get_metadata(&self) -> BTreeMap<String, String> {
+        let mut metadata = self.rows.get_metadata();
+        metadata.insert("sourceProvider".to_string(), "provider_xlsx".to_string());
+        metadata.insert("sourceFormat".to_string(), "xlsx".to_string());
+        metadata
+    }
+
+    fn parse(&mut self, _data: &str) -> Result<(), NormalizationError> {
+        Err(NormalizationError::Parse("xlsx workbooks must be parsed from bytes".into()))
+    }
+
+    fn parse_bytes(&mut self, data: &[u8], _encoding: Option<&str>) -> Result<(), NormalizationError> {
+        let mut workbook = calamine::open_workbook_from_rs::<Xlsx<_>, _>(Cursor::new(data))
+            .map_err(|err| NormalizationError::Parse(err.to_string()))?;
+        let sheet = match &self.layout.sheet {
+            Some(sheet) => sheet.clone(),
+            None => workbook.sheet_names().first().cloned()
+                .ok_or_else(|| NormalizationError::Parse("Workbook has no sheets".into()))?,
+        };
+        let range = workbook.worksheet_range(&sheet)
+            .map_err(|err| NormalizationError::Parse(format!("{}: {}", sheet, err)))?;
+
+        // Row numbers below are 1-based sheet rows, as shown in Excel.
+        let first_row = range.start().map_or(1, |(row, _)| row as usize + 1);
+        let mut rows = range.rows().enumerate()
+            .map(|(index, cells)| (first_row + index, cells))
+            .skip_while(|(line, _)| *line < self.layout.header_row);
+        let headers: Vec<String> = match rows.next() {
+            Some((line, cells)) if line == self.layout.header_row => cells.iter()
+                .map(|cell| self.canonical_header(&cell_text(cell, false).unwrap_or_default()))
+                .collect(),
+            _ => return Err(NormalizationError::Parse("Missing header row".into())),
+        };
+
+        for (line, cells) in rows {
+            if cells.iter().all(|cell| *cell == Data::Empty) {
+                continue;
+            }
+
+            let mut row = BTreeMap::new();
+            let mut error = None;
+            for (header, cell) in headers.iter().zip(cells.iter()) {
+                if header.is_empty() {
+                    continue;
+                }
+                match cell_text(cell, self.layout.date_columns.contains(header)) {
+                    Ok(value) => {
+                        row.insert(header.clone(), value);
+                    },
+                    Err(message) => {
+                        error = Some(format!("{} in column {}", message, header));
+                        break;
+                    },
+                }
+            }
+
+            match error {
+                Some(message) => {
+                    let raw = cells.iter().map(|cell| cell.to_string()).collect::<Vec<_>>().join(",");
+                    self.rows.parse_errors.push(NormalizationError::ParseRow(message, line, raw));
+                },
+                None => {
+                    self.rows.data.push(row);
+                    self.rows.lines.push(line);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&mut self) -> NormalizationError {
+        self.rows.validate()
+    }
+
+    fn convert(&self) -> Vec<NormalizeData> {
+        let metadata = self.get_metadata();
+        let mut output = self.rows.convert();
+        for data in output.iter_mut() {
+            data.metadata.extend(metadata.clone());
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_xlsx_test() {
+        let workbook = include_bytes!("../test-data/data.xlsx");
+        let layout = SheetLayout {
+            header_row: 2,
+            columns: BTreeMap::from([
+                ("Patient".to_string(), "patient_id".to_string()),
+                ("Date".to_string(), "assessment_date".to_string()),
+                ("Metric".to_string(), "metric_name".to_string()),
+                ("Score".to_string(), "metric_value".to_string()),
+                ("Category".to_string(), "category".to_string()),
+            ]),
+            ..SheetLayout::default()
+        };
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {xlsx: layout, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse_bytes(workbook, None).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::None);
+        assert_eq!(handler.rows.data[0]["assessment_date"], "2024-10-15");
+        assert_eq!(handler.rows.data[1]["assessment_date"], "2024-10-15");
+        assert_eq!(handler.rows.data[1]["metric_value"], "4");
+        let converted = handler.convert();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].scores.len(), 2);
+        assert_eq!(converted[0].metadata["sourceFormat"], "xlsx");
+    }
+
+    #[test]
+    fn date_cell_test() {
+        let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day);
+        assert_eq!(excel_serial_date(1.0), date(1900, 1, 1));
+        assert_eq!(excel_serial_date(59.0), date(1900, 2, 28));
+        assert_eq!(excel_serial_date(60.0), None);
+        assert_eq!(excel_serial_date(61.0), date(1900, 3, 1));
+        assert_eq!(excel_serial_date(45580.0), date(2024, 10, 15));
+
+        let date_time = Data::DateTime(calamine::ExcelDateTime::new(45580.4, calamine::ExcelDateTimeType::DateTime, false));
+        assert_eq!(cell_text(&date_time, true), Ok("2024-10-15".to_string()));
+        let iso = Data::DateTimeIso("2024-10-15T09:36:00".into());
+        assert_eq!(cell_text(&iso, true), Ok("2024-10-15".to_string()));
+        assert_eq!(cell_text(&iso, false), Ok("2024-10-15T09:36:00".to_string()));
+    }
+}
@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, HashSet};
+use chrono::{DateTime, NaiveDate, Utc};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::ResolveResult;
+use quick_xml::NsReader;
+
+use crate::config::{MissingTokens, SourceConfig, XmlMapping};
+use crate::model::{NormalizationError, NormalizeData, NormalizeScore, Provider, RecordStatus, ScoreLineage, ScoreValue};
+use crate::provider_hl7::parse_hl7_date;
+
+/// Parses ISO dates and timestamps, and the `YYYYMMDD[HHMM[SS]]` form used by CDA documents.
+fn parse_xml_date(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        return Some(date_time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return date.and_hms_opt(0, 0, 0).map(|date| date.and_utc());
+    }
+
+    parse_hl7_date(value)
+}
+
+/// Assessment type, record id and date of the assessment a record belongs to.
+type RecordKey = (String, Option<String>, DateTime<Utc>);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Step {
+    namespace: Option<String>,
+    local: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Text,
+    Attribute(String),
+}
+
+/// A parsed mapping path, see `XmlMapping`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlPath {
+    absolute: bool,
+    steps: Vec<Step>,
+    target: Target,
+}
+
+impl XmlPath {
+    pub fn parse(path: &str, namespaces: &BTreeMap<String, String>) -> Result<Self, String> {
+        let path = path.trim();
+        let mut steps = Vec::new();
+        let mut target = Target::Text;
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty() && *part != ".").collect();
+        for (index, part) in parts.iter().enumerate() {
+            let is_last = index + 1 == parts.len();
+            if let Some(attribute) = part.strip_prefix('@').filter(|_| is_last) {
+                let local = attribute.split_once(':').map_or(attribute, |(_, local)| local);
+                target = Target::Attribute(local.to_string());
+            } else if *part == "text()" && is_last {
+                target = Target::Text;
+            } else {
+                let step = match part.split_once(':') {
+                    Some((prefix, local)) => Step {
+                        namespace: Some(namespaces.get(prefix).cloned()
+                            .ok_or_else(|| format!("Unknown namespace prefix {} in {}", prefix, path))?),
+                        local: local.to_string(),
+                    },
+                    None => Step { namespace: None, local: part.to_string() },
+                };
+                steps.push(step);
+            }
+        }
+
+        Ok(Self { absolute: path.starts_with('/'), steps, target })
+    }
+
+    fn matches(&self, elements: &[Element]) -> bool {
+        self.steps.len() == elements.len() &&
+            self.steps.iter().zip(elements).all(|(step, element)| {
+                (step.local == "*" || step.local == element.local) &&
+                    (step.namespace.is_none() || step.namespace == element.namespace)
+            })
+    }
+}
+
+/// An open element while streaming, with its positional path such as `/a[1]/b[2]`.
+struct Element {
+    namespace: Option<String>,
+    local: String,
+    path: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Field {
+    PatientId,
+    AssessmentType,
+    AssessmentDate,
+    Dimension,
+    Value,
+    RecordId,
+    Status,
+}
+
+struct OpenScore {
+    depth: usize,
+    path: String,
+    fields: BTreeMap<Field, String>,
+}
+
+struct OpenRecord {
+    depth: usize,
+    /// Line of the record's start tag.
+    line: usize,
+    path: String,
+    fields: BTreeMap<Field, String>,
+    scores: Vec<(String, BTreeMap<Field, String>)>,
+}
+
+/// The parsed paths of an `XmlMapping`.
+struct MappingPaths {
+    record: XmlPath,
+    score: XmlPath,
+    fields: Vec<(Field, XmlPath)>,
+}
+
+/// A score found in a record, with `path` locating its element in the document.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub path: String,
+    pub patient_id: String,
+    pub assessment_type: String,
+    pub assessment_date: String,
+    pub dimension: String,
+    pub value: String,
+    pub record_id: String,
+    pub status: String,
+}
+
+pub struct ProviderHandler {
+    pub mapping: XmlMapping,
+    pub data: Vec<Observation>,
+    pub error_index: HashSet<usize>,
+    pub parse_errors: Vec<NormalizationError>,
+    pub missing: MissingTokens,
+}
+
+impl ProviderHandler {
+    pub fn with_source(source: SourceConfig) -> Self {
+        Self {
+            mapping: source.xml,
+            data: Vec::new(),
+            error_index: HashSet::new(),
+            parse_errors: Vec::new(),
+            missing: source.missing,
+        }
+    }
+
+    /// A score value, or a missing value when it is one of the source's missing tokens.
+    fn score_value(&self, value: &str) -> Option<ScoreValue> {
+        match self.missing.reason(value) {
+            Some(reason) => Some(ScoreValue::Missing(reason)),
+            None => ScoreValue::parse_number(value),
+        }
+    }
+
+    /// Path of the value of the score element at `path`, e.g. `/results[1]/score[2]/@value`.
+    fn value_path(&self, path: &str) -> String {
+        match self.mapping.value.trim().trim_start_matches("./") {
+            "" | "." | "text()" => path.to_string(),
+            value => format!("{}/{}", path, value),
+        }
+    }
+
+    fn paths(&self) -> Result<MappingPaths, NormalizationError> {
+        let parse = |path: &str| XmlPath::parse(path, &self.mapping.namespaces).map_err(NormalizationError::Parse);
+        let record = parse(&self.mapping.record)?;
+        let score = parse(&self.mapping.score)?;
+        let fields = vec![
+            (Field::PatientId, parse(&self.mapping.patient_id)?),
+            (Field::AssessmentType, parse(&self.mapping.assessment_type)?),
+            (Field::AssessmentDate, parse(&self.mapping.assessment_date)?),
+            (Field::Dimension, parse(&self.mapping.dimension)?),
+            (Field::Value, parse(&self.mapping.value)?),
+            (Field::RecordId, parse(&self.mapping.record_id)?),
+            (Field::Status, parse(&self.mapping.status)?),
+        ];
+        Ok(MappingPaths { record, score, fields })
+    }
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element.attributes().flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.trim().to_string())
+}
+
+impl Provider for ProviderHandler {
+    fn get_metadata(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("sourceProvider".to_string(), "provider_xml".to_string()),
+            ("sourceFormat".to_string(), "xml".to_string()),
+            ("ingestedAt".to_string(), Utc::now().to_rfc3339(),),
+            ("version".to_string(), "1.0".to_string()),
+        ])
+    }
+
+    fn parse(&mut self, data: &str) -> Result<(), NormalizationError> {
+        let MappingPaths { record: record_path, score: score_path, fields: field_paths } = self.paths()?;
+        let mut reader = NsReader::from_str(data);
+        reader.config_mut().trim_text(true);
+
+        let mut stack: Vec<Element> = Vec::new();
+        let mut child_counts: Vec<BTreeMap<String, usize>> = vec![BTreeMap::new()];
+        // Values matched by absolute paths outside any record apply to the records after them.
+        let mut document: BTreeMap<Field, String> = BTreeMap::new();
+        let mut record: Option<OpenRecord> = None;
+        let mut score: Option<OpenScore> = None;
+        let mut captures: Vec<(usize, Field, String)> = Vec::new();
+        loop {
+            let position = reader.buffer_position();
+            let (namespace, event) = reader.read_resolved_event()
+                .map_err(|err| NormalizationError::Parse(format!("{} after byte {}", err, position)))?;
+            let (start, is_empty) = match &event {
+                Event::Start(start) => (Some(start.clone()), false),
+                Event::Empty(start) => (Some(start.clone()), true),
+                _ => (None, false),
+            };
+
+            if let Some(start) = start {
+                let local = String::from_utf8_lossy(start.local_name().as_ref()).to_string();
+                let index = child_counts.last_mut().map_or(1, |counts| {
+                    let count = counts.entry(local.clone()).or_default();
+                    *count += 1;
+                    *count
+                });
+                let path = format!("{}/{}[{}]", stack.last().map_or("", |parent| parent.path.as_str()), local, index);
+                stack.push(Element {
+                    namespace: match namespace {
+                        ResolveResult::Bound(namespace) => Some(String::from_utf8_lossy(namespace.as_ref()).to_string()),
+                        _ => None,
+                    },
+                    local,
+                    path: path.clone(),
+                });
+                child_counts.push(BTreeMap::new());
+                let depth = stack.len();
+
+                if record.is_none() && record_path.matches(&stack) {
+                    // The position is before any whitespace skipped ahead of the tag.
+                    let start = data[position as usize..].find('<').map_or(position as usize, |offset| position as usize + offset);
+                    let line = data[..start].matches('\n').count() + 1;
+                    record = Some(OpenRecord { depth, line, path: path.clone(), fields: document.clone(), scores: Vec::new() });
+                }
+                if let Some(record) = &record && score.is_none() && depth > record.depth &&
+                    score_path.matches(&stack[record.depth..]) {
+                    score = Some(OpenScore { depth, path, fields: BTreeMap::new() });
+                }
+
+                for (field, field_path) in &field_paths {
+                    let elements = match (field, &record, &score) {
+                        (Field::Dimension | Field::Value, _, Some(score)) => &stack[score.depth..],
+                        (Field::Dimension | Field::Value, _, None) => continue,
+                        _ if field_path.absolute => &stack[..],
+                        (_, Some(record), _) => &stack[record.depth..],
+                        _ => continue,
+                    };
+                    if !field_path.matches(elements) {
+                        continue;
+                    }
+                    match &field_path.target {
+                        Target::Attribute(name) => {
+                            let value = attribute(&start, name).unwrap_or_default();
+                            match (field, &mut record, &mut score) {
+                                (Field::Dimension | Field::Value, _, Some(score)) => score.fields.insert(*field, value),
+                                (_, Some(record), _) => record.fields.insert(*field, value),
+                                _ => document.insert(*field, value),
+                            };
+                        },
+                        Target::Text => captures.push((depth, *field, String::new())),
+                    }
+                }
+            }
+
+            match &event {
+                Event::Text(text) => {
+                    let text = text.unescape().map_err(|err| NormalizationError::Parse(err.to_string()))?;
+                    for (_, _, value) in captures.iter_mut().filter(|(depth, _, _)| *depth == stack.len()) {
+                        value.push_str(&text);
+                    }
+                },
+                Event::CData(text) => {
+                    let text = String::from_utf8_lossy(text.as_ref()).to_string();
+                    for (_, _, value) in captures.iter_mut().filter(|(depth, _, _)| *depth == stack.len()) {
+                        value.push_str(&text);
+                    }
+                },
+                Event::Eof => break,
+                _ => {},
+            }
+
+            if matches!(event, Event::End(_)) || is_empty {
+                let depth = stack.len();
+                while captures.last().is_some_and(|(capture_depth, _, _)| *capture_depth == depth) {
+                    let (_, field, value) = captures.pop().unwrap_or((depth, Field::Value, String::new()));
+                    let value = value.trim().to_string();
+                    match (field, &mut record, &mut score) {
+                        (Field::Dimension | Field::Value, _, Some(score)) => score.fields.insert(field, value),
+                        (_, Some(record), _) => record.fields.insert(field, value),
+                        _ => document.insert(field, value),
+                    };
+                }
+                if score.as_ref().is_some_and(|score| score.depth == depth) &&
+                    let (Some(open_score), Some(record)) = (score.take(), &mut record) {
+                    record.scores.push((open_score.path, open_score.fields));
+                }
+                if record.as_ref().is_some_and(|record| record.depth == depth) &&
+                    let Some(record) = record.take() {
+                    if record.scores.is_empty() {
+                        self.parse_errors.push(NormalizationError::ParseRow("Record has no scores".into(), record.line, record.path.clone()));
+                    }
+                    let field = |fields: &BTreeMap<Field, String>, field| fields.get(&field).cloned().unwrap_or_default();
+                    for (path, fields) in record.scores {
+                        self.data.push(Observation {
+                            path,
+                            patient_id: field(&record.fields, Field::PatientId),
+                            assessment_type: field(&record.fields, Field::AssessmentType),
+                            assessment_date: field(&record.fields, Field::AssessmentDate),
+                            dimension: field(&fields, Field::Dimension),
+                            value: field(&fields, Field::Value),
+                            record_id: field(&record.fields, Field::RecordId),
+                            status: field(&record.fields, Field::Status),
+                        });
+                    }
+                }
+                stack.pop();
+                child_counts.pop();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate(&mut self) -> NormalizationError {
+        let mut output = self.parse_errors.clone();
+        for (index, data) in self.data.iter().enumerate() {
+            let message = if data.patient_id.is_empty() {
+                Some("has no patient id")
+            } else if data.assessment_type.is_empty() {
+                Some("has no assessment type")
+            } else if parse_xml_date(&data.assessment_date).is_none() {
+                Some("has no valid assessment date")
+            } else if data.dimension.is_empty() {
+                Some("has no dimension")
+            } else if self.score_value(&data.value).is_none() {
+                Some("value is not a number")
+            } else if RecordStatus::parse(&data.status).is_none() {
+                Some("status is not amended or retracted")
+            } else {
+                None
+            };
+
+            if let Some(message) = message {
+                output.push(NormalizationError::Validate(format!("{} {}", data.path, message), index));
+                self.error_index.insert(index);
+            }
+        }
+        if output.is_empty() {NormalizationError::None} else {NormalizationError::Aggregate(output)}
+    }
+
+    fn convert(&self) -> Vec<NormalizeData> {
+        let metadata = self.get_metadata();
+        let mut patients: BTreeMap<String, BTreeMap<RecordKey, NormalizeData>> = BTreeMap::new();
+        for (index, data) in self.data.iter().enumerate() {
+            if self.error_index.contains(&index) {
+                continue;
+            }
+
+            let assessments = patients.entry(data.patient_id.clone()).or_default();
+            let record_id = Some(data.record_id.clone()).filter(|record_id| !record_id.is_empty());
+            // Records of one type and id on different dates are separate assessments.
+            let date = parse_xml_date(&data.assessment_date).unwrap_or_else(Utc::now);
+            let normalized_data = assessments.entry((data.assessment_type.clone(), record_id.clone(), date)).or_insert_with(|| {
+                let mut normalized_data = NormalizeData::new(data.patient_id.clone(), data.assessment_type.clone(), date, metadata.clone());
+                normalized_data.record_id = record_id;
+                normalized_data
+            });
+            normalized_data.status = normalized_data.status.max(RecordStatus::parse(&data.status).unwrap_or_default());
+            normalized_data.scores.push(NormalizeScore {
+                dimension: data.dimension.clone(),
+                value: self.score_value(&data.value).unwrap(),
+                scale: "0-100".into(),
+                derived: false,
+                interpretation: None,
+                lineage: Some(ScoreLineage::new(index, None, self.value_path(&data.path),
+                    data.value.clone(), if self.missing.reason(&data.value).is_some() {"missing token"} else {"none"})),
+            });
+        }
+
+        let mut output = Vec::new();
+        for (_, assessments) in patients {
+            for (_, assessment) in assessments {
+                output.push(assessment);
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_xml_test() {
+        let document = include_str!("../test-data/data.xml");
+        let mapping = XmlMapping {
+            namespaces: BTreeMap::from([
+                ("cda".to_string(), "urn:hl7-org:v3".to_string()),
+                ("v".to_string(), "urn:vendor:scores".to_string()),
+            ]),
+            record: "/cda:ClinicalDocument/cda:component/cda:structuredBody/cda:section".into(),
+            patient_id: "/cda:ClinicalDocument/cda:recordTarget/cda:patientRole/cda:id/@extension".into(),
+            assessment_type: "cda:code/@code".into(),
+            assessment_date: "cda:effectiveTime/@value".into(),
+            score: "v:score".into(),
+            dimension: "@name".into(),
+            value: ".".into(),
+            ..XmlMapping::default()
+        };
+
+        let mut handler = ProviderHandler::with_source(SourceConfig {xml: mapping, ..SourceConfig::default()});
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(document).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::Validate(
+                "/ClinicalDocument[1]/component[1]/structuredBody[1]/section[2]/score[2] value is not a number".into(), 3),
+        ]));
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].patientId, "P123x");
+        assert_eq!(converted[0].assessmentType, "behavioral_screening");
+        assert_eq!(converted[0].scores.len(), 2);
+        assert_eq!(converted[1].assessmentDate, "2024-10-16T00:00:00+00:00");
+        assert_eq!(converted[1].scores.len(), 1);
+    }
+
+    #[test]
+    fn provider_xml_default_mapping_test() {
+        let document = r#"<assessments>
+            <assessment type="cognitive" date="2024-10-15">
+                <patient id="P123x"/>
+                <score name="memory">85</score>
+                <score name="processing"><![CDATA[72.50]]></score>
+            </assessment>
+        </assessments>"#;
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        let provider: &mut dyn Provider = &mut handler as &mut dyn Provider;
+        assert!(provider.parse(document).is_ok());
+        assert_eq!(provider.validate(), NormalizationError::None);
+        let converted = provider.convert();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(converted[0].scores.len(), 2);
+        assert_eq!(converted[0].scores[1].value, ScoreValue::Decimal(crate::model::Decimal {units: 7250, precision: 2}));
+    }
+
+    #[test]
+    fn provider_xml_dates_test() {
+        let document = r#"<assessments>
+            <assessment type="cognitive" date="2024-10-15"><patient id="P123x"/><score name="memory">85</score></assessment>
+            <assessment type="cognitive" date="2024-11-15"><patient id="P123x"/><score name="memory">80</score></assessment>
+        </assessments>"#;
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        assert!(handler.parse(document).is_ok());
+        assert_eq!(handler.validate(), NormalizationError::None);
+        let converted = handler.convert();
+        let assessments: Vec<(&str, usize)> = converted.iter().map(|data| (data.assessmentDate.as_str(), data.scores.len())).collect();
+        assert_eq!(assessments, vec![("2024-10-15T00:00:00+00:00", 1), ("2024-11-15T00:00:00+00:00", 1)]);
+    }
+
+    #[test]
+    fn provider_xml_no_scores_test() {
+        let document = r#"<assessments>
+            <assessment type="cognitive" date="2024-10-15"><patient id="P123x"/></assessment>
+            <assessment type="cognitive" date="2024-11-15"><patient id="P123x"/><score name="memory">80</score></assessment>
+        </assessments>"#;
+
+        let mut handler = ProviderHandler::with_source(SourceConfig::default());
+        assert!(handler.parse(document).is_ok());
+        assert_eq!(handler.validate(), NormalizationError::Aggregate(vec![
+            NormalizationError::ParseRow("Record has no scores".into(), 2, "/assessments[1]/assessment[1]".into()),
+        ]));
+        assert_eq!(handler.convert().len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+use serde::{Serialize, Deserialize};
+
+use crate::model::{Decimal, NormalizeData, ScoreValue};
+
+/// Scale that providers rescaling their source values map onto when the catalogue gives no
+/// target range.
+pub const TARGET_RANGE: Range = Range {min: 0.0, max: 100.0};
+
+/// An inclusive range of valid values.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Range {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Range {
+    pub fn new(min: f64, max: f64) -> Self {
+        Self {min, max}
+    }
+
+    pub fn contains(&self, value: f64) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+
+    /// Reads a scale label such as `0-100` or `-3-3`. Labels that are not a range, e.g. `t`, give None.
+    pub fn parse_scale(scale: &str) -> Option<Self> {
+        let split = scale.char_indices().skip(1).find(|(_, c)| *c == '-')?.0;
+        let (min, max) = (scale[..split].trim().parse().ok()?, scale[split + 1..].trim().parse().ok()?);
+        (min < max).then_some(Self {min, max})
+    }
+
+    /// The range as a scale label, e.g. `0-100`.
+    pub fn scale(&self) -> String {
+        format!("{}-{}", self.min, self.max)
+    }
+}
+
+/// How a provider maps the source values of a dimension onto its target scale: linearly, from the
+/// source range onto the target range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scaling {
+    pub source: Range,
+    pub target: Range,
+}
+
+impl Scaling {
+    /// The whole factor of a scaling between ranges that both start at zero, e.g. 10 from 0-10
+    /// onto 0-100.
+    fn factor(&self) -> Option<i64> {
+        let factor = (self.target.max - self.target.min) / (self.source.max - self.source.min);
+        (self.source.min == 0.0 && self.target.min == 0.0 && factor >= 1.0 && factor.fract() == 0.0 && factor < i64::MAX as f64)
+            .then_some(factor as i64)
+    }
+
+    pub fn map(&self, value: f64) -> f64 {
+        self.target.min + (value - self.source.min) * (self.target.max - self.target.min) / (self.source.max - self.source.min)
+    }
+
+    /// Scales a numeric value. Whole factors keep the precision of decimals; other scalings give
+    /// decimals to 2 places. Missing values stay missing.
+    pub fn apply(&self, value: &ScoreValue) -> Option<ScoreValue> {
+        if let Some(factor) = self.factor() {
+            return value.checked_mul(factor);
+        }
+        match value {
+            ScoreValue::Missing(reason) => Some(ScoreValue::Missing(*reason)),
+            value => Decimal::round_f64(self.map(value.as_f64()?), 2).map(ScoreValue::Decimal),
+        }
+    }
+
+    /// The transform `apply` makes, for lineage: `none`, `x10` or e.g. `1..5 onto 0..100`.
+    pub fn transform(&self) -> String {
+        match self.factor() {
+            Some(1) => "none".to_string(),
+            Some(factor) => format!("x{}", factor),
+            None => format!("{} onto {}", self.source, self.target),
+        }
+    }
+}
+
+impl std::fmt::Display for Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..{}", self.min, self.max)
+    }
+}
+
+/// Valid values of one dimension of an assessment type: `source` for values as read from the
+/// file and `target` for normalized values.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RangeEntry {
+    pub assessment_type: String,
+    pub dimension: String,
+    pub source: Option<Range>,
+    pub target: Option<Range>,
+}
+
+/// Reference ranges keyed by assessment type and dimension. Earlier entries win, so entries of a
+/// source come before the global ones.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(transparent)]
+pub struct RangeCatalog(pub Vec<RangeEntry>);
+
+/// Whether a value was checked as read from the source or after normalization.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RangeStage {
+    Source,
+    Target,
+}
+
+/// A value outside its reference range. `record` is the index of the source record for source
+/// values and of the normalized assessment for target values.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RangeIssue {
+    pub stage: RangeStage,
+    pub record: usize,
+    pub assessment_type: String,
+    pub dimension: String,
+    pub value: String,
+    pub range: String,
+}
+
+impl RangeCatalog {
+    fn find(&self, assessment_type: &str, dimension: &str, stage: RangeStage) -> Option<Range> {
+        self.0.iter()
+            .filter(|entry| entry.assessment_type == assessment_type && entry.dimension == dimension)
+            .find_map(|entry| match stage {
+                RangeStage::Source => entry.source,
+                RangeStage::Target => entry.target,
+            })
+    }
+
+    /// Checks that every range has its minimum below its maximum.
+    pub fn validate(&self) -> Result<(), String> {
+        for entry in &self.0 {
+            if entry.source.iter().chain(entry.target.iter()).any(|range| range.min >= range.max) {
+                return Err(format!("range of {} {} must have min below max", entry.assessment_type, entry.dimension));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scaling of a dimension from its source range, or `default` when the catalogue has none,
+    /// onto its target range, or `TARGET_RANGE`.
+    pub fn scaling(&self, assessment_type: &str, dimension: &str, default: Range) -> Scaling {
+        Scaling {
+            source: self.find(assessment_type, dimension, RangeStage::Source).unwrap_or(default),
+            target: self.find(assessment_type, dimension, RangeStage::Target).unwrap_or(TARGET_RANGE),
+        }
+    }
+
+    /// Checks a numeric value against the catalogue range, or `default` when the catalogue has
+    /// none. Missing, categorical and text values are not range-checked.
+    pub fn check(
+        &self,
+        stage: RangeStage,
+        record: usize,
+        assessment_type: &str,
+        dimension: &str,
+        value: &ScoreValue,
+        default: Option<Range>) -> Option<RangeIssue> {
+        let range = self.find(assessment_type, dimension, stage).or(default)?;
+        let number = value.as_f64()?;
+        if range.contains(number) {
+            return None;
+        }
+
+        Some(RangeIssue {
+            stage,
+            record,
+            assessment_type: assessment_type.to_string(),
+            dimension: dimension.to_string(),
+            value: match value {
+                ScoreValue::Decimal(value) => value.to_string(),
+                _ => number.to_string(),
+            },
+            range: range.to_string(),
+        })
+    }
+
+    /// Checks normalized scores against their target ranges, or else the range of the scale they
+    /// are labelled with, such as `0-100`.
+    pub fn check_normalized(&self, normalized: &[NormalizeData]) -> Vec<RangeIssue> {
+        let mut output = Vec::new();
+        for (index, data) in normalized.iter().enumerate() {
+            for score in &data.scores {
+                output.extend(self.check(RangeStage::Target, index, &data.assessmentType, &score.dimension, &score.value,
+                    Range::parse_scale(&score.scale)));
+            }
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+    use crate::model::NormalizeScore;
+
+    #[test]
+    fn range_catalog_test() {
+        let catalog = RangeCatalog(vec![
+            RangeEntry {
+                assessment_type: "cognitive".into(),
+                dimension: "memory".into(),
+                source: Some(Range::new(0.0, 10.0)),
+                target: Some(Range::new(0.0, 100.0)),
+            },
+        ]);
+
+        let value = ScoreValue::parse_number("10.5").unwrap();
+        let issue = catalog.check(RangeStage::Source, 2, "cognitive", "memory", &value, None).unwrap();
+        assert_eq!(issue.value, "10.5");
+        assert_eq!(issue.range, "0..10");
+        assert!(catalog.check(RangeStage::Source, 0, "cognitive", "memory", &ScoreValue::Integer(7), None).is_none());
+        assert!(catalog.check(RangeStage::Source, 0, "cognitive", "speed", &ScoreValue::Integer(70), None).is_none());
+        assert!(catalog.check(RangeStage::Source, 0, "cognitive", "speed", &ScoreValue::Integer(70),
+            Some(Range::new(0.0, 10.0))).is_some());
+
+        let mut normalized = NormalizeData::new("P1".into(), "cognitive".into(), Utc::now(), BTreeMap::new());
+        normalized.scores.push(NormalizeScore {dimension: "memory".into(), value: ScoreValue::Integer(850), scale: "0-100".into(), derived: false, interpretation: None, lineage: None});
+        normalized.scores.push(NormalizeScore {dimension: "speed".into(), value: ScoreValue::Integer(875), scale: "0-100".into(), derived: false, interpretation: None, lineage: None});
+        normalized.scores.push(NormalizeScore {dimension: "speed_t".into(), value: ScoreValue::Integer(875), scale: "t".into(), derived: false, interpretation: None, lineage: None});
+        let issues = catalog.check_normalized(&[normalized]);
+        assert_eq!(issues.iter().map(|issue| (issue.dimension.as_str(), issue.range.as_str())).collect::<Vec<_>>(),
+            vec![("memory", "0..100"), ("speed", "0..100")]);
+        assert_eq!(issues[0].stage, RangeStage::Target);
+
+        assert_eq!(Range::parse_scale("-3-3"), Some(Range::new(-3.0, 3.0)));
+        let scaling = catalog.scaling("cognitive", "speed", Range::new(1.0, 5.0));
+        assert_eq!(scaling.apply(&ScoreValue::Integer(2)), Some(ScoreValue::Decimal(Decimal {units: 2500, precision: 2})));
+        assert_eq!(scaling.transform(), "1..5 onto 0..100");
+        assert_eq!(catalog.scaling("cognitive", "memory", Range::new(0.0, 100.0)).transform(), "x10");
+    }
+}
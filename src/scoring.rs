@@ -0,0 +1,213 @@
+use serde::{Serialize, Deserialize};
+
+use crate::model::{Decimal, NormalizeData, NormalizeScore, ScoreLineage, ScoreValue};
+use crate::ranges::{Range, RangeCatalog};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScoringRule {
+    /// Sum of the answered items.
+    Sum,
+    /// Mean of the answered items.
+    Mean,
+    /// Mean of the answered items times the number of items, so the sum with unanswered items
+    /// filled in at the mean.
+    Prorated,
+}
+
+/// A derived score computed from item responses or earlier derived scores.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Subscale {
+    /// Dimension of the derived score.
+    pub name: String,
+    /// Dimensions of the item scores, or of subscales listed before this one.
+    pub items: Vec<String>,
+    /// Items scored in reverse, as `min + max - value` of `item_range` on the scale of the
+    /// normalized item.
+    #[serde(default)]
+    pub reverse: Vec<String>,
+    pub rule: ScoringRule,
+    /// Fewest answered items to score the subscale, defaults to all of them.
+    #[serde(default)]
+    pub min_answered: Option<usize>,
+    /// Decimal places of mean and prorated scores.
+    #[serde(default = "default_precision")]
+    pub precision: u32,
+    #[serde(default)]
+    pub scale: String,
+}
+
+fn default_precision() -> u32 {
+    2
+}
+
+/// How an instrument is scored. `version` identifies the formulas and is recorded with every
+/// scored assessment.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Scoring {
+    pub version: String,
+    /// Range of item responses as read from the source, before any provider scaling, needed to
+    /// reverse-key items.
+    #[serde(default)]
+    pub item_range: Option<Range>,
+    pub subscales: Vec<Subscale>,
+}
+
+impl Scoring {
+    /// Checks that every reverse-keyed item can be reversed, and that subscales only use
+    /// subscales listed before them.
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, subscale) in self.subscales.iter().enumerate() {
+            if let Some(item) = subscale.items.iter().find(|item| self.subscales[index..].iter().any(|later| &later.name == *item)) {
+                return Err(format!("subscale {} uses {}, which is not listed before it", subscale.name, item));
+            }
+            if !subscale.reverse.is_empty() && self.item_range.is_none() {
+                return Err(format!("subscale {} has reverse-keyed items but no item_range", subscale.name));
+            }
+            if let Some(item) = subscale.reverse.iter().find(|item| !subscale.items.contains(item)) {
+                return Err(format!("subscale {} reverses {}, which is not one of its items", subscale.name, item));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the derived scores of an assessment, skipping subscales with too few answered items.
+    /// Derived scores already present are recomputed rather than used as items. `source_range`
+    /// is the default source range of providers that scale their values onto the ranges of
+    /// `ranges`; `item_range` is scaled the same way before reversing items. Values of other
+    /// providers are reversed on `item_range` as they are.
+    pub fn score(&self, data: &mut NormalizeData, ranges: &RangeCatalog, source_range: Option<Range>) {
+        data.scores.retain(|score| !score.derived);
+        let mut unscored = Vec::new();
+        for subscale in &self.subscales {
+            let mut values = Vec::new();
+            let mut answered = Vec::new();
+            let mut exact = true;
+            for item in &subscale.items {
+                let value = data.scores.iter()
+                    .find(|score| &score.dimension == item)
+                    .and_then(|score| score.value.as_f64().map(|number| (number, &score.value)));
+                let Some((mut number, value)) = value else {
+                    continue;
+                };
+                if subscale.reverse.contains(item) && let Some(range) = self.item_range {
+                    number = match source_range {
+                        Some(source_range) => {
+                            let scaling = ranges.scaling(&data.assessmentType, item, source_range);
+                            scaling.map(range.min) + scaling.map(range.max) - number
+                        },
+                        None => range.min + range.max - number,
+                    };
+                }
+                exact &= matches!(value, ScoreValue::Integer(_)) && number.fract() == 0.0;
+                values.push(number);
+                answered.push(item.as_str());
+            }
+
+            let min_answered = subscale.min_answered.unwrap_or(subscale.items.len()).max(1);
+            if values.len() < min_answered {
+                unscored.push(subscale.name.clone());
+                continue;
+            }
+
+            let sum: f64 = values.iter().sum();
+            let mean = sum / values.len() as f64;
+            let value = match subscale.rule {
+                ScoringRule::Sum if exact => Some(ScoreValue::Integer(sum as i64)),
+                ScoringRule::Sum => Decimal::round_f64(sum, subscale.precision).map(ScoreValue::Decimal),
+                ScoringRule::Mean => Decimal::round_f64(mean, subscale.precision).map(ScoreValue::Decimal),
+                ScoringRule::Prorated if values.len() == subscale.items.len() && exact => Some(ScoreValue::Integer(sum as i64)),
+                ScoringRule::Prorated => Decimal::round_f64(mean * subscale.items.len() as f64, subscale.precision)
+                    .map(ScoreValue::Decimal),
+            };
+            let mut transform = format!("{:?} of {} items", subscale.rule, answered.len()).to_lowercase();
+            let reversed: Vec<&str> = answered.iter().copied().filter(|item| subscale.reverse.iter().any(|reverse| reverse == item)).collect();
+            if !reversed.is_empty() {
+                transform.push_str(&format!(", reversing {}", reversed.join(",")));
+            }
+            match value {
+                Some(value) => data.scores.push(NormalizeScore {
+                    dimension: subscale.name.clone(),
+                    value,
+                    scale: subscale.scale.clone(),
+                    derived: true,
+                    interpretation: None,
+                    lineage: Some(ScoreLineage::derived(answered.join(","), format!("{} (scoring {})", transform, self.version))),
+                }),
+                None => unscored.push(subscale.name.clone()),
+            }
+        }
+
+        data.metadata.insert("scoringVersion".to_string(), self.version.clone());
+        if unscored.is_empty() {
+            data.metadata.remove("unscoredSubscales");
+        } else {
+            data.metadata.insert("unscoredSubscales".to_string(), unscored.join(","));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+    use crate::model::MissingReason;
+
+    #[test]
+    fn scoring_test() {
+        let scoring: Scoring = serde_json::from_str(r#"{
+            "version": "mood-2024.1",
+            "item_range": {"min": 0, "max": 3},
+            "subscales": [
+                {"name": "low_mood", "items": ["q1", "q2", "q3"], "reverse": ["q3"], "rule": "sum"},
+                {"name": "worry", "items": ["q4", "q5", "q6"], "rule": "prorated", "min_answered": 2},
+                {"name": "sleep", "items": ["q7", "q8"], "rule": "mean"},
+                {"name": "total", "items": ["low_mood", "worry"], "rule": "sum"}
+            ]
+        }"#).unwrap();
+        assert!(scoring.validate().is_ok());
+
+        let mut data = NormalizeData::new("P1".into(), "mood".into(), Utc::now(), BTreeMap::new());
+        for (item, value) in [("q1", 2), ("q2", 1), ("q3", 0), ("q4", 1), ("q5", 2), ("q7", 3)] {
+            data.scores.push(NormalizeScore {dimension: item.into(), value: ScoreValue::Integer(value), scale: "0-3".into(), derived: false, interpretation: None, lineage: None});
+        }
+        data.scores.push(NormalizeScore {
+            dimension: "q6".into(),
+            value: ScoreValue::Missing(MissingReason::Refused),
+            scale: "0-3".into(),
+            derived: false,
+            interpretation: None,
+            lineage: None,
+        });
+        scoring.score(&mut data, &RangeCatalog::default(), None);
+        scoring.score(&mut data, &RangeCatalog::default(), None);
+
+        let derived: Vec<(&str, ScoreValue)> = data.scores.iter()
+            .filter(|score| score.derived)
+            .map(|score| (score.dimension.as_str(), score.value.clone()))
+            .collect();
+        assert_eq!(derived, vec![
+            ("low_mood", ScoreValue::Integer(6)),
+            ("worry", ScoreValue::Decimal(Decimal {units: 450, precision: 2})),
+            ("total", ScoreValue::Decimal(Decimal {units: 1050, precision: 2})),
+        ]);
+        assert_eq!(data.metadata["scoringVersion"], "mood-2024.1");
+        assert_eq!(data.metadata["unscoredSubscales"], "sleep");
+
+        // Items scaled x10 from 0-10 onto 0-100 are reversed on 0-30.
+        let mut data = NormalizeData::new("P1".into(), "mood".into(), Utc::now(), BTreeMap::new());
+        for (item, value) in [("q1", 20), ("q2", 10), ("q3", 30)] {
+            data.scores.push(NormalizeScore {dimension: item.into(), value: ScoreValue::Integer(value), scale: "0-100".into(), derived: false, interpretation: None, lineage: None});
+        }
+        scoring.score(&mut data, &RangeCatalog::default(), Some(Range::new(0.0, 10.0)));
+        assert_eq!(data.scores.iter().find(|score| score.dimension == "low_mood").unwrap().value, ScoreValue::Integer(30));
+
+        let mut invalid = scoring.clone();
+        invalid.subscales.swap(0, 3);
+        assert_eq!(invalid.validate(), Err("subscale total uses low_mood, which is not listed before it".to_string()));
+        invalid.subscales[0].items = vec!["total".into()];
+        assert_eq!(invalid.validate(), Err("subscale total uses total, which is not listed before it".to_string()));
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{Serialize, Deserialize};
+
+use crate::model::NormalizeData;
+
+/// Clinical interpretation of a score, from least to most severe.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Normal,
+    Borderline,
+    Clinical,
+}
+
+/// Which way a score gets worse.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    HigherIsWorse,
+    LowerIsWorse,
+}
+
+/// Cut-offs of one dimension. A score at or beyond `clinical` is clinical, else at or beyond
+/// `borderline` is borderline, in the direction of severity.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SeverityRule {
+    pub dimension: String,
+    pub direction: Direction,
+    pub borderline: f64,
+    pub clinical: f64,
+}
+
+impl SeverityRule {
+    pub fn validate(&self) -> Result<(), String> {
+        let ordered = match self.direction {
+            Direction::HigherIsWorse => self.borderline <= self.clinical,
+            Direction::LowerIsWorse => self.borderline >= self.clinical,
+        };
+        if ordered {
+            Ok(())
+        } else {
+            Err(format!("cut-offs of {} are not in the direction of severity", self.dimension))
+        }
+    }
+
+    pub fn classify(&self, value: f64) -> Severity {
+        let beyond = |cutoff: f64| match self.direction {
+            Direction::HigherIsWorse => value >= cutoff,
+            Direction::LowerIsWorse => value <= cutoff,
+        };
+        if beyond(self.clinical) {
+            Severity::Clinical
+        } else if beyond(self.borderline) {
+            Severity::Borderline
+        } else {
+            Severity::Normal
+        }
+    }
+}
+
+/// Interprets every numeric score that has a rule, and flags the assessment with the most severe
+/// interpretation. Assessments without interpreted scores are not flagged.
+pub fn interpret(rules: &[SeverityRule], data: &mut NormalizeData) {
+    for score in data.scores.iter_mut() {
+        score.interpretation = rules.iter()
+            .find(|rule| rule.dimension == score.dimension)
+            .zip(score.value.as_f64())
+            .map(|(rule, value)| rule.classify(value));
+    }
+
+    data.flag = data.scores.iter().filter_map(|score| score.interpretation).max();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{MissingReason, ScoreValue};
+    use crate::testing::{assessment, config, normalize, score};
+
+    #[test]
+    fn severity_test() {
+        let rules = vec![
+            SeverityRule {dimension: "anxiety".into(), direction: Direction::HigherIsWorse, borderline: 60.0, clinical: 70.0},
+            SeverityRule {dimension: "memory_t".into(), direction: Direction::LowerIsWorse, borderline: 40.0, clinical: 30.0},
+            SeverityRule {dimension: "social".into(), direction: Direction::HigherIsWorse, borderline: 60.0, clinical: 70.0},
+        ];
+        assert!(rules.iter().all(|rule| rule.validate().is_ok()));
+
+        let mut data = assessment("P1", "cognitive", "2024-10-15T09:00:00Z", &[("anxiety", 65)]);
+        data.scores.push(score("memory_t", ScoreValue::parse_number("28.5").unwrap()));
+        data.scores.push(score("social", ScoreValue::Missing(MissingReason::Refused)));
+        data.scores.push(score("attention", ScoreValue::Integer(90)));
+        interpret(&rules, &mut data);
+
+        let interpretations: Vec<Option<Severity>> = data.scores.iter().map(|score| score.interpretation).collect();
+        assert_eq!(interpretations, vec![Some(Severity::Borderline), Some(Severity::Clinical), None, None]);
+        assert_eq!(data.flag, Some(Severity::Clinical));
+    }
+
+    #[test]
+    fn severity_data_test() {
+        let normalized = normalize(&config(), "data.a");
+        let interpretations: Vec<(&str, Option<Severity>)> = normalized[0].scores.iter()
+            .map(|score| (score.dimension.as_str(), score.interpretation))
+            .collect();
+        assert_eq!(interpretations, vec![
+            ("anxiety", Some(Severity::Clinical)),
+            ("attention", None),
+            ("social", Some(Severity::Borderline)),
+        ]);
+        assert_eq!(normalized[0].flag, Some(Severity::Clinical));
+    }
+}
@@ -0,0 +1,602 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter};
+
+use crate::dedup::Candidate;
+use crate::model::{Decimal, MissingReason, NormalizationError, NormalizeData, RecordStatus, ScoreLineage, ScoreValue};
+use crate::severity::Severity;
+
+/// Schema of the embedded database. Assessments of a source file replace those loaded from it
+/// before, so reprocessing a file does not duplicate them. A file is the same one when its name,
+/// archive and checksum match, so a new file under an earlier file's name is stored next to it.
+pub const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+
+-- One row per patient, with the latest demographics seen.
+CREATE TABLE IF NOT EXISTS patients (
+    patient_id TEXT PRIMARY KEY,
+    date_of_birth TEXT,
+    sex TEXT
+);
+
+-- One row per normalized assessment.
+CREATE TABLE IF NOT EXISTS assessments (
+    id INTEGER PRIMARY KEY,
+    patient_id TEXT NOT NULL REFERENCES patients(patient_id),
+    assessment_type TEXT NOT NULL,
+    -- As normalized, in RFC 3339.
+    assessment_date TEXT NOT NULL,
+    -- In UTC to the second, for date ranges. Null when the date is invalid, or when the source gave
+    -- none and assessment_date is the processing time.
+    assessment_time TEXT,
+    provider TEXT,
+    source_file TEXT,
+    source_archive TEXT,
+    -- SHA-256 of the input file, telling apart files sent under the same name.
+    source_checksum TEXT,
+    -- complete or partial, null when the instrument is not in the catalogue.
+    completeness TEXT,
+    -- Most severe interpretation of the scores.
+    flag TEXT,
+    -- Fingerprint of the patient, type, date and source scores, see dedup.rs.
+    fingerprint TEXT,
+    -- Identifier of the record in the source, shared by its versions from the same provider.
+    record_id TEXT,
+    -- original, amended or retracted.
+    status TEXT NOT NULL DEFAULT 'original',
+    version INTEGER NOT NULL DEFAULT 1,
+    -- Fingerprint of the version this one replaces.
+    supersedes TEXT,
+    -- Fingerprint of the version that replaced this one. Only assessments that are neither
+    -- superseded nor retracted are current.
+    superseded_by TEXT
+);
+CREATE INDEX IF NOT EXISTS assessments_patient ON assessments(patient_id, assessment_type);
+CREATE INDEX IF NOT EXISTS assessments_source ON assessments(source_file, source_archive, source_checksum);
+CREATE INDEX IF NOT EXISTS assessments_fingerprint ON assessments(fingerprint);
+CREATE INDEX IF NOT EXISTS assessments_record ON assessments(provider, record_id);
+
+-- One row per score, in the order of the normalized assessment.
+CREATE TABLE IF NOT EXISTS scores (
+    assessment_id INTEGER NOT NULL REFERENCES assessments(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    dimension TEXT NOT NULL,
+    -- integer, decimal, categorical, text or missing.
+    value_type TEXT NOT NULL,
+    -- Exact text of the value, null when missing.
+    value TEXT,
+    -- Numeric value of integers and decimals.
+    number REAL,
+    -- Missing reason, e.g. refused.
+    missing TEXT,
+    scale TEXT NOT NULL,
+    derived INTEGER NOT NULL,
+    interpretation TEXT,
+    -- Where the score was read from, as the JSON of its ScoreLineage, see model.rs.
+    lineage TEXT,
+    PRIMARY KEY (assessment_id, position)
+);
+CREATE INDEX IF NOT EXISTS scores_dimension ON scores(dimension);
+
+-- Metadata of the assessment, including the lineage keys provider, sourceFile, sourceArchive and
+-- sourceChecksum.
+CREATE TABLE IF NOT EXISTS metadata (
+    assessment_id INTEGER NOT NULL REFERENCES assessments(id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (assessment_id, key)
+);
+";
+
+/// Name of a unit enum variant as serialized, e.g. `refused`.
+pub fn serde_name<T: Serialize>(value: &T) -> Option<String> {
+    serde_json::to_value(value).ok()?.as_str().map(str::to_string)
+}
+
+fn from_serde_name<T: serde::de::DeserializeOwned>(name: &str) -> Option<T> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+}
+
+/// The `value_type`, `value` and `missing` columns of a score.
+fn value_columns(value: &ScoreValue) -> (&'static str, Option<String>, Option<String>) {
+    match value {
+        ScoreValue::Integer(value) => ("integer", Some(value.to_string()), None),
+        ScoreValue::Decimal(value) => ("decimal", Some(value.to_string()), None),
+        ScoreValue::Category(value) => ("categorical", Some(value.clone()), None),
+        ScoreValue::Text(value) => ("text", Some(value.clone()), None),
+        ScoreValue::Missing(reason) => ("missing", None, serde_name(reason)),
+    }
+}
+
+fn score_value(value_type: &str, value: Option<String>, missing: Option<String>) -> Option<ScoreValue> {
+    match (value_type, value) {
+        ("integer", Some(value)) => value.parse().ok().map(ScoreValue::Integer),
+        ("decimal", Some(value)) => Decimal::parse(&value).map(ScoreValue::Decimal),
+        ("categorical", Some(value)) => Some(ScoreValue::Category(value)),
+        ("text", Some(value)) => Some(ScoreValue::Text(value)),
+        ("missing", _) => Some(ScoreValue::Missing(
+            missing.and_then(|reason| from_serde_name(&reason)).unwrap_or(MissingReason::Unknown))),
+        _ => None,
+    }
+}
+
+/// Metadata keys that identify the source file of an assessment, see `Store::insert`.
+const SOURCE_KEYS: [&str; 4] = ["provider", "sourceFile", "sourceArchive", "sourceChecksum"];
+
+fn utc_time(date: &str) -> Option<String> {
+    let date = DateTime::parse_from_rfc3339(date).ok()?;
+    Some(date.with_timezone(&Utc).to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn store_error(err: rusqlite::Error) -> NormalizationError {
+    NormalizationError::Unknown(format!("database: {}", err))
+}
+
+/// Filters of a score query. Dates are RFC 3339 or `YYYY-MM-DD`, and a date range includes both
+/// of its days. Assessments whose source gave no date never match a date range. Only current
+/// assessments match unless `all_versions` is set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScoreQuery {
+    pub patient: Option<String>,
+    pub provider: Option<String>,
+    pub assessment_type: Option<String>,
+    pub dimension: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Include superseded and retracted assessments.
+    pub all_versions: bool,
+}
+
+impl ScoreQuery {
+    fn bound(date: &str, end: bool) -> Result<String, NormalizationError> {
+        if let Some(time) = utc_time(date) {
+            return Ok(time);
+        }
+        let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| NormalizationError::Parse(format!("invalid date: {}", date)))?;
+        let time = if end {day.and_hms_opt(23, 59, 59)} else {day.and_hms_opt(0, 0, 0)};
+        Ok(time.unwrap_or_default().and_utc().to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    /// The `WHERE` clause and its parameters.
+    fn filter(&self) -> Result<(String, Vec<String>), NormalizationError> {
+        let mut clauses = Vec::new();
+        if !self.all_versions {
+            clauses.push("a.superseded_by IS NULL AND a.status != 'retracted'");
+        }
+        let mut values = Vec::new();
+        let columns = [
+            ("a.patient_id = ?", &self.patient),
+            ("a.provider = ?", &self.provider),
+            ("a.assessment_type = ?", &self.assessment_type),
+            ("s.dimension = ?", &self.dimension),
+        ];
+        for (clause, value) in columns {
+            if let Some(value) = value {
+                clauses.push(clause);
+                values.push(value.clone());
+            }
+        }
+        if let Some(from) = &self.from {
+            clauses.push("a.assessment_time >= ?");
+            values.push(Self::bound(from, false)?);
+        }
+        if let Some(to) = &self.to {
+            clauses.push("a.assessment_time <= ?");
+            values.push(Self::bound(to, true)?);
+        }
+
+        let filter = if clauses.is_empty() {String::new()} else {format!("WHERE {}", clauses.join(" AND "))};
+        Ok((filter, values))
+    }
+}
+
+/// One score of a query result.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScoreRow {
+    pub patient_id: String,
+    pub assessment_type: String,
+    pub assessment_date: String,
+    pub provider: Option<String>,
+    pub source_file: Option<String>,
+    pub record_id: Option<String>,
+    pub version: u32,
+    pub status: RecordStatus,
+    pub dimension: String,
+    #[serde(flatten)]
+    pub value: ScoreValue,
+    pub scale: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub derived: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interpretation: Option<Severity>,
+    /// Left out of CSV output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<ScoreLineage>,
+}
+
+/// Writes query results as CSV, with the value split into `value`, `valueType` and `missing`.
+pub fn write_csv<W: std::io::Write>(writer: W, rows: &[ScoreRow]) -> Result<(), NormalizationError> {
+    let csv_error = |err: csv::Error| NormalizationError::Unknown(format!("csv: {}", err));
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record([
+        "patientId", "assessmentType", "assessmentDate", "provider", "sourceFile", "recordId", "version", "status", "dimension",
+        "value", "valueType", "missing", "scale", "derived", "interpretation",
+    ]).map_err(csv_error)?;
+    for row in rows {
+        let (value_type, value, missing) = value_columns(&row.value);
+        writer.write_record([
+            row.patient_id.as_str(),
+            &row.assessment_type,
+            &row.assessment_date,
+            row.provider.as_deref().unwrap_or_default(),
+            row.source_file.as_deref().unwrap_or_default(),
+            row.record_id.as_deref().unwrap_or_default(),
+            &row.version.to_string(),
+            &serde_name(&row.status).unwrap_or_default(),
+            &row.dimension,
+            &value.unwrap_or_default(),
+            value_type,
+            &missing.unwrap_or_default(),
+            &row.scale,
+            if row.derived {"true"} else {"false"},
+            &row.interpretation.and_then(|interpretation| serde_name(&interpretation)).unwrap_or_default(),
+        ]).map_err(csv_error)?;
+    }
+
+    writer.flush().map_err(|err| NormalizationError::Unknown(format!("csv: {}", err)))
+}
+
+/// Embedded SQLite database of every normalized assessment.
+pub struct Store {
+    connection: Connection,
+}
+
+impl Store {
+    /// Opens the database, creating it and its tables when needed.
+    pub fn open(path: &str) -> Result<Self, NormalizationError> {
+        let connection = Connection::open(path)
+            .map_err(|err| NormalizationError::Unknown(format!("{}: {}", path, err)))?;
+        connection.execute_batch(SCHEMA).map_err(store_error)?;
+        Ok(Self {connection})
+    }
+
+    /// Stores the assessments of one source file, replacing those stored from the same file
+    /// before. Files with the same name and other contents are kept.
+    pub fn insert(&mut self, normalized: &[NormalizeData]) -> Result<(), NormalizationError> {
+        let transaction = self.connection.transaction().map_err(store_error)?;
+        let sources: BTreeSet<[Option<String>; 4]> = normalized.iter()
+            .map(|data| SOURCE_KEYS.map(|key| data.metadata.get(key).cloned()))
+            .collect();
+        for [provider, source_file, source_archive, source_checksum] in sources {
+            transaction.execute(
+                "UPDATE assessments SET superseded_by = NULL WHERE superseded_by IN (SELECT fingerprint FROM assessments
+                     WHERE provider IS ?1 AND source_file IS ?2 AND source_archive IS ?3 AND source_checksum IS ?4)",
+                params![provider, source_file, source_archive, source_checksum]).map_err(store_error)?;
+            transaction.execute(
+                "DELETE FROM assessments
+                 WHERE provider IS ?1 AND source_file IS ?2 AND source_archive IS ?3 AND source_checksum IS ?4",
+                params![provider, source_file, source_archive, source_checksum]).map_err(store_error)?;
+        }
+
+        for data in normalized {
+            let demographics = data.demographics.clone().unwrap_or_default();
+            transaction.execute(
+                "INSERT INTO patients (patient_id, date_of_birth, sex) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (patient_id) DO UPDATE SET
+                     date_of_birth = coalesce(excluded.date_of_birth, date_of_birth),
+                     sex = coalesce(excluded.sex, sex)",
+                params![
+                    data.patientId,
+                    demographics.date_of_birth.map(|date| date.to_string()),
+                    demographics.sex.map(|sex| sex.name()),
+                ]).map_err(store_error)?;
+            transaction.execute(
+                "INSERT INTO assessments (patient_id, assessment_type, assessment_date, assessment_time, provider,
+                     source_file, source_archive, source_checksum, completeness, flag, fingerprint, record_id, status, version,
+                     supersedes)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                params![
+                    data.patientId,
+                    data.assessmentType,
+                    data.assessmentDate,
+                    if data.date_unknown {None} else {utc_time(&data.assessmentDate)},
+                    data.metadata.get("provider"),
+                    data.metadata.get("sourceFile"),
+                    data.metadata.get("sourceArchive"),
+                    data.metadata.get("sourceChecksum"),
+                    data.completeness.as_ref().and_then(|completeness| serde_name(&completeness.status)),
+                    data.flag.and_then(|flag| serde_name(&flag)),
+                    data.metadata.get("fingerprint"),
+                    data.record_id,
+                    serde_name(&data.status),
+                    data.version,
+                    data.supersedes,
+                ]).map_err(store_error)?;
+            let id = transaction.last_insert_rowid();
+            if let Some((fingerprint, superseded)) = data.metadata.get("fingerprint").zip(data.supersedes.as_ref()) {
+                transaction.execute(
+                    "UPDATE assessments SET superseded_by = ?1 WHERE fingerprint = ?2 AND id != ?3",
+                    params![fingerprint, superseded, id]).map_err(store_error)?;
+            }
+
+            for (position, score) in data.scores.iter().enumerate() {
+                let (value_type, value, missing) = value_columns(&score.value);
+                transaction.execute(
+                    "INSERT INTO scores (assessment_id, position, dimension, value_type, value, number, missing, scale,
+                         derived, interpretation, lineage)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                    params![
+                        id,
+                        position as i64,
+                        score.dimension,
+                        value_type,
+                        value,
+                        score.value.as_f64(),
+                        missing,
+                        score.scale,
+                        score.derived,
+                        score.interpretation.and_then(|interpretation| serde_name(&interpretation)),
+                        score.lineage.as_ref().map(|lineage| serde_json::json!(lineage).to_string()),
+                    ]).map_err(store_error)?;
+            }
+            for (key, value) in &data.metadata {
+                transaction.execute(
+                    "INSERT INTO metadata (assessment_id, key, value) VALUES (?1, ?2, ?3)",
+                    params![id, key, value]).map_err(store_error)?;
+            }
+        }
+
+        transaction.commit().map_err(store_error)
+    }
+
+    /// Stored assessments of the same patient and type as `data`, for deduplication. Those of the
+    /// same source file are left out, since storing `data` replaces them.
+    pub fn candidates(&self, data: &NormalizeData) -> Result<Vec<Candidate>, NormalizationError> {
+        let mut statement = self.connection.prepare(
+            "SELECT a.id, a.fingerprint, a.assessment_time, a.source_file, s.dimension, s.value_type, s.value, s.missing
+             FROM assessments a LEFT JOIN scores s ON s.assessment_id = a.id AND NOT s.derived
+             WHERE a.patient_id = ?5 AND a.assessment_type = ?6 AND a.fingerprint IS NOT NULL AND a.status != 'retracted'
+                 AND NOT (a.provider IS ?1 AND a.source_file IS ?2 AND a.source_archive IS ?3 AND a.source_checksum IS ?4)
+                 AND (a.superseded_by IS NULL OR a.superseded_by IN (SELECT fingerprint FROM assessments
+                     WHERE provider IS ?1 AND source_file IS ?2 AND source_archive IS ?3 AND source_checksum IS ?4))
+             ORDER BY a.id, s.position").map_err(store_error)?;
+        let lineage = SOURCE_KEYS.map(|key| data.metadata.get(key));
+        let mut rows = statement.query(params![lineage[0], lineage[1], lineage[2], lineage[3], data.patientId, data.assessmentType])
+            .map_err(store_error)?;
+
+        let mut candidates: Vec<(i64, Candidate)> = Vec::new();
+        while let Some(row) = rows.next().map_err(store_error)? {
+            let id: i64 = row.get(0).map_err(store_error)?;
+            if candidates.last().is_none_or(|(last, _)| *last != id) {
+                let time: Option<String> = row.get(2).map_err(store_error)?;
+                candidates.push((id, Candidate {
+                    fingerprint: row.get(1).map_err(store_error)?,
+                    assessment_time: time.and_then(|time| DateTime::parse_from_rfc3339(&time).ok()).map(|time| time.to_utc()),
+                    scores: BTreeMap::new(),
+                    source_file: row.get(3).map_err(store_error)?,
+                }));
+            }
+            let dimension: Option<String> = row.get(4).map_err(store_error)?;
+            let Some(dimension) = dimension else {
+                continue;
+            };
+            let value_type: String = row.get(5).map_err(store_error)?;
+            let value = score_value(&value_type, row.get(6).map_err(store_error)?, row.get(7).map_err(store_error)?)
+                .ok_or_else(|| NormalizationError::Parse("database: invalid stored score value".to_string()))?;
+            if let Some((_, candidate)) = candidates.last_mut() {
+                candidate.scores.insert(dimension, serde_json::json!(value));
+            }
+        }
+
+        Ok(candidates.into_iter().map(|(_, candidate)| candidate).collect())
+    }
+
+    /// Numbers the versions of the assessments of one file. An assessment is a new version of the
+    /// current assessment it supersedes as a duplicate, or else of the current assessment with the
+    /// same provider and record id from another file.
+    pub fn assign_versions(&self, normalized: &mut [NormalizeData]) -> Result<(), NormalizationError> {
+        for data in normalized.iter_mut() {
+            let lineage = SOURCE_KEYS.map(|key| data.metadata.get(key));
+            let previous: Option<(String, u32)> = match (&data.supersedes, &data.record_id) {
+                (Some(fingerprint), _) => self.connection.query_row(
+                    "SELECT fingerprint, version FROM assessments WHERE fingerprint = ?1 ORDER BY id DESC LIMIT 1",
+                    params![fingerprint], |row| Ok((row.get(0)?, row.get(1)?))),
+                (None, Some(record_id)) => self.connection.query_row(
+                    "SELECT fingerprint, version FROM assessments
+                     WHERE provider IS ?1 AND record_id = ?5 AND fingerprint IS NOT NULL
+                         AND NOT (source_file IS ?2 AND source_archive IS ?3 AND source_checksum IS ?4)
+                         AND (superseded_by IS NULL OR superseded_by IN (SELECT fingerprint FROM assessments
+                             WHERE provider IS ?1 AND source_file IS ?2 AND source_archive IS ?3 AND source_checksum IS ?4))
+                     ORDER BY id DESC LIMIT 1",
+                    params![lineage[0], lineage[1], lineage[2], lineage[3], record_id], |row| Ok((row.get(0)?, row.get(1)?))),
+                (None, None) => continue,
+            }.optional().map_err(store_error)?;
+
+            if let Some((fingerprint, version)) = previous {
+                data.version = version + 1;
+                data.supersedes = Some(fingerprint);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scores matching a query, by patient and assessment date.
+    pub fn query(&self, query: &ScoreQuery) -> Result<Vec<ScoreRow>, NormalizationError> {
+        let (filter, values) = query.filter()?;
+        let sql = format!(
+            "SELECT a.patient_id, a.assessment_type, a.assessment_date, a.provider, a.source_file, s.dimension,
+                 s.value_type, s.value, s.missing, s.scale, s.derived, s.interpretation, a.record_id, a.version, a.status,
+                 s.lineage
+             FROM scores s JOIN assessments a ON a.id = s.assessment_id
+             {}
+             ORDER BY a.patient_id, a.assessment_time, a.assessment_type, a.id, s.position", filter);
+        let mut statement = self.connection.prepare(&sql).map_err(store_error)?;
+        let rows = statement.query_map(params_from_iter(values.iter()), |row| {
+            let value_type: String = row.get(6)?;
+            let value = score_value(&value_type, row.get(7)?, row.get(8)?);
+            let Some(value) = value else {
+                return Ok(None);
+            };
+            let interpretation: Option<String> = row.get(11)?;
+            let status: String = row.get(14)?;
+            let lineage: Option<String> = row.get(15)?;
+            Ok(Some(ScoreRow {
+                patient_id: row.get(0)?,
+                assessment_type: row.get(1)?,
+                assessment_date: row.get(2)?,
+                provider: row.get(3)?,
+                source_file: row.get(4)?,
+                record_id: row.get(12)?,
+                version: row.get(13)?,
+                status: from_serde_name(&status).unwrap_or_default(),
+                dimension: row.get(5)?,
+                value,
+                scale: row.get(9)?,
+                derived: row.get(10)?,
+                interpretation: interpretation.and_then(|interpretation| from_serde_name(&interpretation)),
+                lineage: lineage.and_then(|lineage| serde_json::from_str(&lineage).ok()),
+            }))
+        }).map_err(store_error)?;
+
+        let mut output = Vec::new();
+        for row in rows {
+            match row.map_err(store_error)? {
+                Some(row) => output.push(row),
+                None => return Err(NormalizationError::Parse("database: invalid stored score value".to_string())),
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{assessment, config, normalize, score};
+
+    #[test]
+    fn store_test() {
+        let mut store = Store::open(":memory:").unwrap();
+        let cognitive = |date, memory| {
+            let mut data = assessment("P123b", "cognitive", date, &[]);
+            data.metadata.insert("provider".to_string(), "c".to_string());
+            data.metadata.insert("sourceFile".to_string(), "data.c".to_string());
+            data.scores.push(score("memory", memory));
+            data.scores.push(score("processing", ScoreValue::Missing(MissingReason::Refused)));
+            data
+        };
+        let normalized = vec![
+            cognitive("2024-10-14T09:30:00Z", ScoreValue::Integer(85)),
+            cognitive("2024-10-15T09:30:00Z", ScoreValue::Decimal(Decimal {units: 725, precision: 1})),
+        ];
+        store.insert(&normalized).unwrap();
+        store.insert(&normalized).unwrap();
+        let count: i64 = store.connection.query_row("SELECT count(*) FROM assessments", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 2);
+
+        let rows = store.query(&ScoreQuery {
+            patient: Some("P123b".into()),
+            dimension: Some("memory".into()),
+            from: Some("2024-10-15".into()),
+            ..ScoreQuery::default()
+        }).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].value, ScoreValue::Decimal(Decimal {units: 725, precision: 1}));
+        assert_eq!(rows[0].provider.as_deref(), Some("c"));
+
+        let rows = store.query(&ScoreQuery {to: Some("2024-10-14".into()), ..ScoreQuery::default()}).unwrap();
+        assert_eq!(rows[1].value, ScoreValue::Missing(MissingReason::Refused));
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &rows).unwrap();
+        assert!(String::from_utf8(csv).unwrap().contains("P123b,cognitive,2024-10-14T09:30:00+00:00,c,data.c,,1,original,processing,,missing,refused,0-100,false,"));
+    }
+
+    #[test]
+    fn versions_test() {
+        let mut store = Store::open(":memory:").unwrap();
+        let record = |file: &str, status, memory| {
+            let mut data = assessment("P1", "cognitive", "2024-10-15T09:00:00Z", &[("memory", memory)]);
+            data.metadata.insert("provider".to_string(), "hl7".to_string());
+            data.metadata.insert("sourceFile".to_string(), file.to_string());
+            data.metadata.insert("fingerprint".to_string(), format!("{}-{}", file, memory));
+            data.record_id = Some("ORD1".into());
+            data.status = status;
+            vec![data]
+        };
+        let current = |store: &Store| store.query(&ScoreQuery::default()).unwrap().iter()
+            .map(|row| (row.version, row.value.clone()))
+            .collect::<Vec<_>>();
+
+        let mut original = record("monday.hl7", RecordStatus::Original, 85);
+        store.assign_versions(&mut original).unwrap();
+        store.insert(&original).unwrap();
+        let mut amended = record("tuesday.hl7", RecordStatus::Amended, 58);
+        store.assign_versions(&mut amended).unwrap();
+        assert_eq!((amended[0].version, amended[0].supersedes.as_deref()), (2, Some("monday.hl7-85")));
+        store.insert(&amended).unwrap();
+        assert_eq!(current(&store), vec![(2, ScoreValue::Integer(58))]);
+
+        // Reprocessing the amendment numbers it the same way.
+        let mut amended = record("tuesday.hl7", RecordStatus::Amended, 58);
+        store.assign_versions(&mut amended).unwrap();
+        assert_eq!(amended[0].version, 2);
+        store.insert(&amended).unwrap();
+
+        let mut retracted = record("wednesday.hl7", RecordStatus::Retracted, 58);
+        store.assign_versions(&mut retracted).unwrap();
+        store.insert(&retracted).unwrap();
+        assert!(current(&store).is_empty());
+        let all = store.query(&ScoreQuery {all_versions: true, ..ScoreQuery::default()}).unwrap();
+        assert_eq!(all.iter().map(|row| (row.version, row.status)).collect::<Vec<_>>(),
+            vec![(1, RecordStatus::Original), (2, RecordStatus::Amended), (3, RecordStatus::Retracted)]);
+    }
+
+    #[test]
+    fn undated_query_test() {
+        let config = config();
+        let mut store = Store::open(":memory:").unwrap();
+        store.insert(&normalize(&config, "data.a")).unwrap();
+        store.insert(&normalize(&config, "data.c")).unwrap();
+        let providers = |query: &ScoreQuery| store.query(query).unwrap().iter()
+            .filter_map(|row| row.provider.clone())
+            .collect::<BTreeSet<_>>();
+
+        // Provider A gives no dates, so its assessments only match queries without a date range.
+        assert_eq!(providers(&ScoreQuery::default()), BTreeSet::from(["a".to_string(), "c".to_string()]));
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        assert_eq!(providers(&ScoreQuery {from: Some("2024-10-15".into()), ..ScoreQuery::default()}), BTreeSet::from(["c".to_string()]));
+        assert!(providers(&ScoreQuery {from: Some(today.clone()), to: Some(today), ..ScoreQuery::default()}).is_empty());
+    }
+
+    #[test]
+    fn same_name_test() {
+        let config = config();
+        let file = |contents: &str| {
+            let input = crate::input::InputFile {name: "scores.c".into(), archive: None, bytes: contents.as_bytes().to_vec()};
+            crate::handle_data(&config, &input).unwrap().0
+        };
+        let mut store = Store::open(":memory:").unwrap();
+        let dedup = crate::dedup::DedupConfig::default();
+        let mut monday = file("patient_id,assessment_date,metric_name,metric_value,category\nP1,2024-10-14,memory,6,cognitive");
+        let mut tuesday = file("patient_id,assessment_date,metric_name,metric_value,category\nP1,2024-10-15,memory,7,cognitive");
+        dedup.deduplicate(&store, &mut monday).unwrap();
+        store.insert(&monday).unwrap();
+        assert!(dedup.deduplicate(&store, &mut tuesday).unwrap().is_empty());
+        store.insert(&tuesday).unwrap();
+
+        // A vendor's daily file under the same name keeps the earlier days, and is compared with them.
+        let dates = |store: &Store| store.query(&ScoreQuery::default()).unwrap().iter()
+            .map(|row| row.assessment_date.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(dates(&store), vec!["2024-10-14T00:00:00+00:00", "2024-10-15T00:00:00+00:00"]);
+        assert_eq!(store.candidates(&tuesday[0]).unwrap().len(), 1);
+
+        // Reading the same file again replaces what was stored from it.
+        store.insert(&file("patient_id,assessment_date,metric_name,metric_value,category\nP1,2024-10-14,memory,6,cognitive")).unwrap();
+        assert_eq!(dates(&store).len(), 2);
+    }
+}
@@ -0,0 +1,42 @@
+//! Helpers shared by the unit tests.
+
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::input::InputFile;
+use crate::instruments::InstrumentCatalog;
+use crate::model::{NormalizeData, NormalizeScore, ScoreValue};
+use crate::norms::NormCatalog;
+
+/// A source score on the 0-100 scale.
+pub fn score(dimension: &str, value: ScoreValue) -> NormalizeScore {
+    NormalizeScore {dimension: dimension.into(), value, scale: "0-100".into(), derived: false, interpretation: None, lineage: None}
+}
+
+/// An assessment dated at an RFC 3339 time, e.g. `2024-10-15T09:00:00Z`, with integer scores.
+pub fn assessment(patient: &str, assessment_type: &str, date: &str, scores: &[(&str, i64)]) -> NormalizeData {
+    let date = DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&Utc);
+    let mut data = NormalizeData::new(patient.into(), assessment_type.into(), date, BTreeMap::new());
+    data.scores = scores.iter().map(|(dimension, value)| score(dimension, ScoreValue::Integer(*value))).collect();
+    data
+}
+
+/// The default configuration with the instruments and norms shipped in the repository.
+pub fn config() -> Config {
+    Config {
+        instruments: InstrumentCatalog::load_dir("instruments").unwrap(),
+        norms: NormCatalog::load_dir("norms").unwrap(),
+        ..Config::default()
+    }
+}
+
+/// Normalizes a file of `test-data` as it would be processed.
+pub fn normalize(config: &Config, file_name: &str) -> Vec<NormalizeData> {
+    let input = InputFile {
+        name: file_name.into(),
+        archive: None,
+        bytes: std::fs::read(format!("test-data/{}", file_name)).unwrap(),
+    };
+    crate::handle_data(config, &input).unwrap().0
+}
@@ -0,0 +1,265 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use chrono::DateTime;
+
+use crate::dedup::Candidate;
+use crate::instruments::InstrumentCatalog;
+use crate::model::{NormalizationError, NormalizeData, RecordStatus, ScoreValue};
+
+/// Smallest reliable change index, in absolute value, of a change beyond measurement error at 95%.
+const RELIABLE_CHANGE_INDEX: f64 = 1.96;
+
+/// Reliability of one dimension, for the Jacobson-Truax reliable change index: the change divided
+/// by `sd * sqrt(2 * (1 - reliability))`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReliableChange {
+    pub dimension: String,
+    /// Standard deviation of the scores in the reference sample.
+    pub sd: f64,
+    /// Test-retest reliability.
+    pub reliability: f64,
+}
+
+impl ReliableChange {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sd <= 0.0 || !(0.0..1.0).contains(&self.reliability) {
+            return Err(format!("reliable change of {} needs a positive sd and a reliability from 0 up to 1", self.dimension));
+        }
+
+        Ok(())
+    }
+
+    pub fn index(&self, change: f64) -> f64 {
+        change / (self.sd * (2.0 * (1.0 - self.reliability)).sqrt())
+    }
+}
+
+/// Every assessment of one patient, oldest first.
+#[derive(Clone, Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(transparent)]
+pub struct PatientHistory(pub Vec<NormalizeData>);
+
+/// File name of a patient in the history and timeline directories.
+fn patient_file(dir: &str, patient_id: &str) -> String {
+    let name: String = patient_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' {c} else {'_'})
+        .collect();
+    format!("{}/{}.json", dir, name)
+}
+
+/// What makes an assessment the same one again: its input file, its fingerprint and its status.
+/// Processing times, such as `ingestedAt`, differ every time a file is processed.
+fn history_key(data: &NormalizeData) -> (Option<&String>, String, RecordStatus) {
+    (data.metadata.get("sourceChecksum"), Candidate::new(data).fingerprint, data.status)
+}
+
+impl PatientHistory {
+    pub fn load(dir: &str, patient_id: &str) -> Result<Self, NormalizationError> {
+        let path = patient_file(dir, patient_id);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|err| NormalizationError::Parse(format!("{}: {}", path, err))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(NormalizationError::Unknown(format!("{}: {}", path, err))),
+        }
+    }
+
+    pub fn save(&self, dir: &str, patient_id: &str) -> Result<(), NormalizationError> {
+        let path = patient_file(dir, patient_id);
+        std::fs::write(&path, serde_json::json!(self).to_string())
+            .map_err(|err| NormalizationError::Unknown(format!("{}: {}", path, err)))
+    }
+
+    /// Adds an assessment unless the same one is already recorded, e.g. when a file is
+    /// processed again, and keeps the assessments in date order. Superseded versions are kept.
+    /// Returns whether the history changed.
+    pub fn add(&mut self, data: &NormalizeData) -> bool {
+        let key = history_key(data);
+        if self.0.iter().any(|recorded| history_key(recorded) == key) {
+            return false;
+        }
+        self.0.push(data.clone());
+        self.0.sort_by_key(|data| DateTime::parse_from_rfc3339(&data.assessmentDate).ok());
+        true
+    }
+
+    /// Assessments that are neither superseded by a later version nor retracted.
+    pub fn current(&self) -> impl Iterator<Item = &NormalizeData> {
+        let superseded: BTreeSet<&String> = self.0.iter().filter_map(|data| data.supersedes.as_ref()).collect();
+        self.0.iter().filter(move |data| data.status != RecordStatus::Retracted &&
+            !data.metadata.get("fingerprint").is_some_and(|fingerprint| superseded.contains(fingerprint)))
+    }
+
+    /// Score trajectories of the current assessments by assessment type and dimension, with the
+    /// change of every numeric score since the previous numeric score of the same dimension.
+    /// Assessments whose source gave no date are listed at their processing time, but neither get
+    /// a change nor count as the previous score, since their place in the series is unknown.
+    pub fn timeline(&self, instruments: &InstrumentCatalog) -> Vec<TimelineSeries> {
+        let mut series: BTreeMap<(String, String), TimelineSeries> = BTreeMap::new();
+        for data in self.current() {
+            for score in &data.scores {
+                let entry = series.entry((data.assessmentType.clone(), score.dimension.clone()))
+                    .or_insert_with(|| TimelineSeries {
+                        assessment_type: data.assessmentType.clone(),
+                        dimension: score.dimension.clone(),
+                        points: Vec::new(),
+                    });
+                let previous = entry.points.iter().rev()
+                    .filter(|point| !point.assessment_date_unknown)
+                    .find_map(|point| point.value.as_f64());
+                let change = previous.zip(score.value.as_f64())
+                    .filter(|_| !data.date_unknown)
+                    .map(|(previous, value)| value - previous);
+                let index = change
+                    .zip(instruments.reliable_change(&data.assessmentType, &score.dimension))
+                    .map(|(change, reliable_change)| reliable_change.index(change));
+                entry.points.push(TimelinePoint {
+                    assessment_date: data.assessmentDate.clone(),
+                    assessment_date_unknown: data.date_unknown,
+                    value: score.value.clone(),
+                    source_file: data.metadata.get("sourceFile").cloned(),
+                    change,
+                    reliable_change_index: index,
+                    reliable_change: index.map(|index| index.abs() >= RELIABLE_CHANGE_INDEX),
+                });
+            }
+        }
+
+        series.into_values().collect()
+    }
+}
+
+/// One score of a patient over time.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineSeries {
+    pub assessment_type: String,
+    pub dimension: String,
+    pub points: Vec<TimelinePoint>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelinePoint {
+    pub assessment_date: String,
+    /// Whether the source gave no date, so that `assessment_date` is the processing time.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub assessment_date_unknown: bool,
+    #[serde(flatten)]
+    pub value: ScoreValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reliable_change_index: Option<f64>,
+    /// Whether the change is beyond measurement error, for dimensions with a known reliability.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reliable_change: Option<bool>,
+}
+
+/// Adds normalized assessments to the history of their patients and rewrites the timeline of
+/// every patient that got a new assessment.
+pub fn record(
+    history_dir: &str,
+    timeline_dir: &str,
+    instruments: &InstrumentCatalog,
+    normalized: &[NormalizeData]) -> Result<(), NormalizationError> {
+    let mut patients: BTreeMap<&str, Vec<&NormalizeData>> = BTreeMap::new();
+    for data in normalized {
+        patients.entry(data.patientId.as_str()).or_default().push(data);
+    }
+
+    for (patient_id, assessments) in patients {
+        let mut history = PatientHistory::load(history_dir, patient_id)?;
+        let mut changed = false;
+        for data in assessments {
+            changed |= history.add(data);
+        }
+        if !changed {
+            continue;
+        }
+
+        history.save(history_dir, patient_id)?;
+        let timeline = serde_json::json!({
+            "patientId": patient_id,
+            "series": history.timeline(instruments),
+        });
+        let path = patient_file(timeline_dir, patient_id);
+        std::fs::write(&path, timeline.to_string())
+            .map_err(|err| NormalizationError::Unknown(format!("{}: {}", path, err)))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruments::Instrument;
+    use crate::model::MissingReason;
+    use crate::testing::{assessment, config, normalize, score};
+
+    #[test]
+    fn timeline_test() {
+        let mut instruments = InstrumentCatalog::default();
+        instruments.0.insert("behavioral_screening".into(), Instrument {
+            assessment_type: "behavioral_screening".into(),
+            version: None,
+            dimensions: Vec::new(),
+            scoring: None,
+            severity: Vec::new(),
+            reliable_change: vec![ReliableChange {dimension: "anxiety".into(), sd: 10.0, reliability: 0.8}],
+        });
+
+        let mut refused = assessment("P1", "behavioral_screening", "2024-03-01T00:00:00Z", &[]);
+        refused.scores.push(score("anxiety", ScoreValue::Missing(MissingReason::Refused)));
+        let mut history = PatientHistory::default();
+        history.add(&assessment("P1", "behavioral_screening", "2024-06-01T00:00:00Z", &[("anxiety", 62)]));
+        history.add(&assessment("P1", "behavioral_screening", "2024-01-01T00:00:00Z", &[("anxiety", 70)]));
+        history.add(&refused);
+        assert!(!history.add(&assessment("P1", "behavioral_screening", "2024-01-01T00:00:00Z", &[("anxiety", 70)])));
+        assert_eq!(history.0.len(), 3);
+
+        let timeline = history.timeline(&instruments);
+        assert_eq!(timeline.len(), 1);
+        let points = &timeline[0].points;
+        assert_eq!(points[0].change, None);
+        assert_eq!(points[1].change, None);
+        assert_eq!(points[2].change, Some(-8.0));
+        assert!((points[2].reliable_change_index.unwrap() + 1.2649).abs() < 1e-4);
+        assert_eq!(points[2].reliable_change, Some(false));
+
+        // An undated assessment processed between the two gets no change and is not the previous score.
+        let mut undated = assessment("P1", "behavioral_screening", "2024-04-01T00:00:00Z", &[("anxiety", 40)]);
+        undated.date_unknown = true;
+        history.add(&undated);
+        let points = &history.timeline(&instruments)[0].points;
+        assert_eq!(points.iter().map(|point| (point.assessment_date_unknown, point.change)).collect::<Vec<_>>(),
+            vec![(false, None), (false, None), (true, None), (false, Some(-8.0))]);
+        assert_eq!(points[2].reliable_change_index, None);
+    }
+
+    #[test]
+    fn history_reprocessing_test() {
+        let config = config();
+        let mut history = PatientHistory::default();
+        for data in normalize(&config, "data.c").iter().filter(|data| data.patientId == "P123c") {
+            assert!(history.add(data));
+        }
+        assert_eq!(history.0.len(), 1);
+        assert_eq!(history.0[0].assessmentDate, "2024-10-15T00:00:00+00:00");
+
+        // Processing the file again stamps a new ingestedAt but adds nothing.
+        for data in normalize(&config, "data.c").iter().filter(|data| data.patientId == "P123c") {
+            assert!(!history.add(data));
+        }
+        assert_eq!(history.0.len(), 1);
+
+        // Provider A gives no dates, so its assessments are matched without them.
+        let undated = normalize(&config, "data.a");
+        assert!(history.add(&undated[0]));
+        assert!(!history.add(&normalize(&config, "data.a")[0]));
+        assert_eq!(history.0.len(), 2);
+    }
+}